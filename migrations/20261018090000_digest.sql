@@ -0,0 +1 @@
+ALTER TABLE notifications ADD COLUMN created_at TIMESTAMP DEFAULT NULL;
@@ -0,0 +1,38 @@
+ALTER TABLE feed_items ADD COLUMN updated_at TIMESTAMP DEFAULT NULL;
+ALTER TABLE feed_items ADD COLUMN content_hash TEXT DEFAULT NULL;
+ALTER TABLE feed_items ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS feed_item_revisions (
+	id INTEGER PRIMARY KEY NOT NULL,
+	feed_item_id INTEGER NOT NULL,
+	revision INTEGER NOT NULL,
+	title TEXT DEFAULT NULL,
+	content_hash TEXT DEFAULT NULL,
+	updated_at TIMESTAMP DEFAULT NULL,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY (feed_item_id) REFERENCES feed_items(id),
+	UNIQUE (feed_item_id, revision) ON CONFLICT IGNORE
+);
+
+-- Notifications are per item revision now, so that updated items can
+-- be sent again; SQLite can't change constraints in place
+CREATE TABLE notifications_new (
+	id INTEGER PRIMARY KEY NOT NULL,
+	feed_item_id INTEGER NOT NULL,
+	webhook_id INTEGER NOT NULL,
+	revision INTEGER NOT NULL DEFAULT 0,
+	sent INTEGER NOT NULL,
+	sent_at TIMESTAMP,
+	created_at TIMESTAMP DEFAULT NULL,
+	FOREIGN KEY (feed_item_id) REFERENCES feed_items(id),
+	FOREIGN KEY (webhook_id) REFERENCES webhooks(id),
+	UNIQUE (feed_item_id, webhook_id, revision) ON CONFLICT IGNORE
+);
+
+INSERT INTO notifications_new (id, feed_item_id, webhook_id, sent, sent_at, created_at)
+SELECT id, feed_item_id, webhook_id, sent, sent_at, created_at
+FROM notifications;
+
+DROP TABLE notifications;
+
+ALTER TABLE notifications_new RENAME TO notifications;
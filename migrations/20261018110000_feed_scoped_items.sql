@@ -0,0 +1,64 @@
+-- Item guids are unique per feed only, so that the same story can be
+-- saved for every feed it appears in. SQLite can't change constraints
+-- in place and foreign keys can't be disabled within the migration's
+-- transaction, so the tables referencing `feed_items` are rebuilt too.
+
+CREATE TABLE feed_items_new (
+	id INTEGER PRIMARY KEY NOT NULL,
+	feed_id INTEGER NOT NULL,
+	title TEXT DEFAULT NULL,
+	link TEXT NOT NULL,
+	guid TEXT NOT NULL,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+	published_at TIMESTAMP DEFAULT NULL,
+	updated_at TIMESTAMP DEFAULT NULL,
+	content_hash TEXT DEFAULT NULL,
+	revision INTEGER NOT NULL DEFAULT 0,
+	FOREIGN KEY (feed_id) REFERENCES feeds(id),
+	UNIQUE (feed_id, guid) ON CONFLICT IGNORE
+);
+
+INSERT INTO feed_items_new (id, feed_id, title, link, guid, created_at, published_at, updated_at, content_hash, revision)
+SELECT id, feed_id, title, link, guid, created_at, published_at, updated_at, content_hash, revision
+FROM feed_items;
+
+CREATE TEMPORARY TABLE notifications_tmp AS SELECT * FROM notifications;
+CREATE TEMPORARY TABLE feed_item_revisions_tmp AS SELECT * FROM feed_item_revisions;
+
+DROP TABLE notifications;
+DROP TABLE feed_item_revisions;
+DROP TABLE feed_items;
+
+ALTER TABLE feed_items_new RENAME TO feed_items;
+
+CREATE TABLE feed_item_revisions (
+	id INTEGER PRIMARY KEY NOT NULL,
+	feed_item_id INTEGER NOT NULL,
+	revision INTEGER NOT NULL,
+	title TEXT DEFAULT NULL,
+	content_hash TEXT DEFAULT NULL,
+	updated_at TIMESTAMP DEFAULT NULL,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY (feed_item_id) REFERENCES feed_items(id),
+	UNIQUE (feed_item_id, revision) ON CONFLICT IGNORE
+);
+
+INSERT INTO feed_item_revisions SELECT * FROM feed_item_revisions_tmp;
+
+CREATE TABLE notifications (
+	id INTEGER PRIMARY KEY NOT NULL,
+	feed_item_id INTEGER NOT NULL,
+	webhook_id INTEGER NOT NULL,
+	revision INTEGER NOT NULL DEFAULT 0,
+	sent INTEGER NOT NULL,
+	sent_at TIMESTAMP,
+	created_at TIMESTAMP DEFAULT NULL,
+	FOREIGN KEY (feed_item_id) REFERENCES feed_items(id),
+	FOREIGN KEY (webhook_id) REFERENCES webhooks(id),
+	UNIQUE (feed_item_id, webhook_id, revision) ON CONFLICT IGNORE
+);
+
+INSERT INTO notifications SELECT * FROM notifications_tmp;
+
+DROP TABLE notifications_tmp;
+DROP TABLE feed_item_revisions_tmp;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS redirects (
+	id INTEGER PRIMARY KEY NOT NULL,
+	url TEXT NOT NULL UNIQUE,
+	target TEXT NOT NULL,
+	created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+ALTER TABLE feeds ADD COLUMN limit_exceeded TEXT DEFAULT NULL;
+ALTER TABLE feeds ADD COLUMN limit_exceeded_at TIMESTAMP DEFAULT NULL;
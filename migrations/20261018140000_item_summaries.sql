@@ -0,0 +1 @@
+ALTER TABLE feed_items ADD COLUMN summary TEXT DEFAULT NULL;
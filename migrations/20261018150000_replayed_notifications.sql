@@ -0,0 +1 @@
+ALTER TABLE notifications ADD COLUMN replayed BOOLEAN NOT NULL DEFAULT false;
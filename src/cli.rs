@@ -0,0 +1,256 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+
+use crate::prelude::*;
+
+#[derive(Parser, Debug)]
+#[clap(version = "0.1.0")]
+pub struct Opts {
+    #[clap(short, long)]
+    pub config: String,
+    /// Format of log messages; the log level can be set with `RUST_LOG`
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    pub log_format: LogFormat,
+    /// Format of the output of commands
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    pub output: OutputFormat,
+    /// The command to run, `run` by default
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Fetch feeds and send notifications
+    Run,
+    /// Validate the config and fetch every feed once, without saving
+    /// items or sending notifications
+    Check,
+    /// Fetch a feed and print the items that would be saved and the
+    /// payloads every webhook would get, without changing the database
+    /// or sending anything
+    Preview {
+        /// Url of a configured feed
+        #[clap(long, required_unless_present = "url", conflicts_with_all = ["kind", "url"])]
+        feed: Option<String>,
+        /// Kind of a feed that isn't configured, "rss" or "atom"
+        #[clap(long, requires = "url")]
+        kind: Option<String>,
+        /// Url of a feed that isn't configured
+        #[clap(long, requires = "kind")]
+        url: Option<String>,
+        /// Webhook url to render payloads for instead of the configured
+        /// ones; can be given more than once
+        #[clap(long = "webhook-url")]
+        webhook_urls: Vec<String>,
+    },
+    /// Send a notification to a webhook right away and print the
+    /// response
+    TestWebhook {
+        /// Name of the webhook in the config, its id in the database or
+        /// its url
+        webhook: String,
+        /// Id of a saved item to send instead of a test item
+        #[clap(long)]
+        item: Option<i64>,
+    },
+    /// Inspect, add and remove feeds
+    #[clap(subcommand)]
+    Feeds(FeedsCommand),
+    /// Inspect, retry and purge notifications
+    #[clap(subcommand)]
+    Notifications(NotificationsCommand),
+    /// Maintain the database
+    #[clap(subcommand)]
+    Db(DbCommand),
+    /// Import feeds from or export them to OPML
+    #[clap(subcommand)]
+    Opml(OpmlCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FeedsCommand {
+    /// List configured feeds and feeds saved in the database
+    List,
+    /// Show a feed and its newest items
+    Show {
+        /// Url or id of the feed
+        feed: String,
+        /// Number of items to show
+        #[clap(long, default_value = "10")]
+        items: i64,
+    },
+    /// Add a feed to the config file
+    Add {
+        /// Kind of the feed, "rss" or "atom"
+        #[clap(long)]
+        kind: String,
+        #[clap(long)]
+        url: String,
+        /// Webhook url to use instead of the global ones; can be given
+        /// more than once
+        #[clap(long = "webhook-url")]
+        webhook_urls: Vec<String>,
+        /// Number of newest items to send when the feed is fetched for
+        /// the first time
+        #[clap(long)]
+        backfill: Option<usize>,
+        /// Send notifications for items changed after publication
+        #[clap(long)]
+        notify_updates: bool,
+    },
+    /// Remove a feed from the config file
+    Remove {
+        /// Url or id of the feed
+        feed: String,
+        /// Also delete the feed with its items and notifications from
+        /// the database
+        #[clap(long)]
+        purge: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum NotificationsCommand {
+    /// List the newest notifications
+    List {
+        /// Only list notifications to the webhook with this id
+        #[clap(long)]
+        webhook: Option<i64>,
+        /// Only list notifications about items of the feed with this id
+        #[clap(long)]
+        feed: Option<i64>,
+        /// Only list notifications that weren't sent yet
+        #[clap(long, conflicts_with = "sent")]
+        pending: bool,
+        /// Only list notifications that were sent
+        #[clap(long)]
+        sent: bool,
+        /// Only list notifications sent, or if pending created, at or
+        /// after this time, e.g. "2024-03-01" or "2024-03-01T12:00:00Z"
+        #[clap(long, value_parser = parse_time)]
+        after: Option<NaiveDateTime>,
+        /// Only list notifications sent, or if pending created, before
+        /// this time
+        #[clap(long, value_parser = parse_time)]
+        before: Option<NaiveDateTime>,
+        /// Number of notifications to list
+        #[clap(long, default_value = "50")]
+        limit: i64,
+    },
+    /// Send pending notifications right away, regardless of digests
+    /// and delivery windows
+    Retry {
+        /// Ids of the notifications to send; all pending ones by default
+        ids: Vec<i64>,
+        /// Only send notifications to the webhook with this id
+        #[clap(long)]
+        webhook: Option<i64>,
+        /// Only send notifications about items of the feed with this id
+        #[clap(long)]
+        feed: Option<i64>,
+    },
+    /// Send notifications that were delivered already again, e.g. after
+    /// an outage of the target
+    Replay {
+        /// Url or id of the webhook
+        #[clap(long)]
+        webhook: Option<String>,
+        /// Id of a feed whose items are sent again; can be given more
+        /// than once
+        #[clap(long = "feed")]
+        feeds: Vec<i64>,
+        /// Only send notifications again that were sent at or after this
+        /// time, e.g. "2024-03-01" or "2024-03-01T12:00:00Z"
+        #[clap(long, value_parser = parse_time)]
+        sent_after: Option<NaiveDateTime>,
+        /// Only send notifications again that were sent before this time
+        #[clap(long, value_parser = parse_time)]
+        sent_before: Option<NaiveDateTime>,
+        /// Send the last N items of the feeds to the webhook instead,
+        /// whether they were sent to it before or not
+        #[clap(long, requires = "webhook", conflicts_with_all = ["sent_after", "sent_before"])]
+        last: Option<usize>,
+    },
+    /// Delete pending notifications, so that they are never sent, or
+    /// old sent ones
+    Purge {
+        /// Only delete notifications to the webhook with this id
+        #[clap(long)]
+        webhook: Option<i64>,
+        /// Only delete notifications about items of the feed with this id
+        #[clap(long)]
+        feed: Option<i64>,
+        /// Delete sent notifications instead of pending ones
+        #[clap(long)]
+        sent: bool,
+        /// Only delete notifications sent, or if pending created, at or
+        /// after this time, e.g. "2024-03-01" or "2024-03-01T12:00:00Z"
+        #[clap(long, value_parser = parse_time)]
+        after: Option<NaiveDateTime>,
+        /// Only delete notifications sent, or if pending created, before
+        /// this time
+        #[clap(long, value_parser = parse_time)]
+        before: Option<NaiveDateTime>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Apply pending migrations
+    Migrate,
+    /// Rebuild the database file to reclaim unused space
+    Vacuum,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum OpmlCommand {
+    /// Add the feeds of an OPML file to the config file, detecting their
+    /// kinds; feeds that are configured already are skipped
+    Import {
+        /// Path of the OPML file
+        file: String,
+        /// Only print what would be added
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Print the configured feeds as OPML, nested by category
+    Export,
+}
+
+/// `OutputFormat` is the format commands print their results in
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Prints `report` to stdout, human-readable or as JSON
+pub fn print_report<T: Serialize + fmt::Display>(format: OutputFormat, report: &T) -> Result<()> {
+    match format {
+        OutputFormat::Text => print!("{}", report),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+    }
+
+    Ok(())
+}
+
+/// Parses a time given on the command line, either RFC 3339 or a date,
+/// to UTC as saved in the database
+pub fn parse_time(s: &str) -> Result<NaiveDateTime> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.naive_utc());
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(d.and_hms_opt(0, 0, 0).unwrap_or_default());
+    }
+
+    Err(anyhow!(
+        "invalid time '{}', expected e.g. \"2024-03-01\" or \"2024-03-01T12:00:00Z\"",
+        s
+    ))
+}
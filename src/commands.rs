@@ -0,0 +1,771 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::prelude::*;
+
+/// Returns the config at `config_path` and the database it points to
+async fn open(config_path: &str) -> Result<(Config, Pool<Sqlite>)> {
+    let config = read_config_file(config_path.to_string())
+        .await
+        .context("failed to read config")?;
+    let db = get_db_pool(&config.db_path()).await?;
+
+    Ok((config, db))
+}
+
+/// Formats an optional value for tables, `-` if unset
+fn or_dash<T: fmt::Display>(value: &Option<T>) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// `CheckReport` is the result of `check`
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub webhooks: Vec<CheckedWebhook>,
+    pub feeds: Vec<CheckedFeed>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckedWebhook {
+    pub url: String,
+    pub kind: Option<&'static str>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckedFeed {
+    pub kind: String,
+    pub url: String,
+    pub items: Option<usize>,
+    pub error: Option<String>,
+    /// Feeds found if `url` is an HTML page
+    #[serde(flatten)]
+    pub discovered: Discovered,
+}
+
+impl CheckReport {
+    pub fn ok(&self) -> bool {
+        self.webhooks.iter().all(|w| w.error.is_none())
+            && self.feeds.iter().all(|f| f.error.is_none())
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for webhook in &self.webhooks {
+            match &webhook.error {
+                None => writeln!(
+                    f,
+                    "ok      webhook {} {}",
+                    or_dash(&webhook.kind),
+                    webhook.url
+                )?,
+                Some(e) => writeln!(f, "FAILED  webhook {}: {}", webhook.url, e)?,
+            }
+        }
+        for feed in &self.feeds {
+            match &feed.error {
+                None => writeln!(
+                    f,
+                    "ok      feed {} {} ({} items)",
+                    feed.kind,
+                    feed.url,
+                    feed.items.unwrap_or_default()
+                )?,
+                Some(e) => writeln!(f, "FAILED  feed {} {}: {}", feed.kind, feed.url, e)?,
+            }
+            write!(f, "{}", feed.discovered)?;
+        }
+        Ok(())
+    }
+}
+
+/// Validates the config at `config_path` and fetches every feed once.
+/// Items aren't saved and nothing is sent, but feeds are saved to the
+/// database like on startup.
+pub async fn check(config_path: &str, output: OutputFormat) -> Result<bool> {
+    let (config, db) = open(config_path).await?;
+    let scheduler = Scheduler::load(config, &db)
+        .await
+        .context("invalid config")?;
+
+    let config = &scheduler.config;
+    let mut webhook_urls: Vec<Secret> = vec![];
+    let configured = config
+        .webhook_urls
+        .iter()
+        .flatten()
+        .chain(
+            config
+                .feeds
+                .iter()
+                .flat_map(|f| f.webhook_urls.iter().flatten()),
+        )
+        .chain(config.webhooks.iter().flatten().map(|w| &w.url));
+    for url in configured {
+        if !webhook_urls.contains(url) {
+            webhook_urls.push(url.clone());
+        }
+    }
+    let client = &scheduler.client;
+    let webhooks = webhook_urls
+        .into_iter()
+        .map(|url| match webhook_from_url(url.clone(), client) {
+            Ok(webhook) => CheckedWebhook {
+                url: url.to_string(),
+                kind: Some(webhook.kind()),
+                error: None,
+            },
+            Err(e) => CheckedWebhook {
+                url: url.to_string(),
+                kind: None,
+                error: Some(error_chain(&e)),
+            },
+        })
+        .collect();
+
+    let mut feeds = vec![];
+    for feed in &scheduler.feeds {
+        let feed = feed.lock().await;
+        let (items, error) = match feed.fetch().await {
+            Ok(items) => (Some(items.len()), None),
+            Err(e) => (None, Some(error_chain(&e))),
+        };
+        feeds.push(CheckedFeed {
+            kind: feed.kind(),
+            url: feed.url(),
+            items,
+            error,
+            discovered: feed.discovered(),
+        });
+    }
+
+    let report = CheckReport { webhooks, feeds };
+    print_report(output, &report)?;
+
+    db.close().await;
+
+    Ok(report.ok())
+}
+
+/// `WebhookTest` is the result of `test-webhook`
+#[derive(Debug, Serialize)]
+pub struct WebhookTest {
+    pub webhook: String,
+    pub kind: &'static str,
+    pub payload: String,
+    #[serde(flatten)]
+    pub delivery: Option<Delivery>,
+    pub error: Option<String>,
+}
+
+impl WebhookTest {
+    pub fn ok(&self) -> bool {
+        match (&self.delivery, &self.error) {
+            (_, Some(_)) => false,
+            (
+                Some(Delivery {
+                    status: Some(status),
+                    ..
+                }),
+                None,
+            ) => (200..300).contains(status),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for WebhookTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "webhook:  {} {}", self.kind, self.webhook)?;
+        writeln!(f, "payload:  {}", self.payload)?;
+        if let Some(delivery) = &self.delivery {
+            writeln!(f, "status:   {}", or_dash(&delivery.status))?;
+            writeln!(f, "response: {}", delivery.body)?;
+        }
+        if let Some(e) = &self.error {
+            writeln!(f, "error:    {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends a test item, or the saved item with the id `item`, to the
+/// webhook with the name, id or url `webhook`
+pub async fn test_webhook(
+    config_path: &str,
+    webhook: &str,
+    item: Option<i64>,
+    output: OutputFormat,
+) -> Result<bool> {
+    let (config, db) = open(config_path).await?;
+    let client = HttpClient::from_config(&config)?;
+
+    let webhook = match (config.webhook_by_name(webhook), webhook.parse::<i64>()) {
+        (Some(named), _) => webhook_from_url(named.url.clone(), &client)?,
+        (None, Ok(id)) => Webhooks::get(&db, id, &client)
+            .await
+            .with_context(|| format!("no webhook with id {}", id))?,
+        (None, Err(_)) => webhook_from_url(Secret::new(webhook.to_string()), &client)
+            .with_context(|| format!("no webhook named '{}'", webhook))?,
+    };
+    let item = match item {
+        Some(id) => FeedItems::get(&db, id)
+            .await
+            .with_context(|| format!("no item with id {}", id))?,
+        None => FeedItem::synthetic(
+            &db,
+            "feedcruncher test notification",
+            "https://github.com/schu/feedcruncher",
+        ),
+    };
+
+    let payload = webhook.render(&item)?;
+    let (delivery, error) = match webhook.deliver(payload.clone()).await {
+        Ok(delivery) => (Some(delivery), None),
+        Err(e) => (None, Some(error_chain(&e))),
+    };
+    let report = WebhookTest {
+        webhook: webhook.url().to_string(),
+        kind: webhook.kind(),
+        payload,
+        delivery,
+        error,
+    };
+    print_report(output, &report)?;
+
+    db.close().await;
+
+    Ok(report.ok())
+}
+
+/// `FeedStatus` is a feed as configured and as saved in the database;
+/// feeds that were removed from the config may still be saved
+#[derive(Debug, Serialize)]
+pub struct FeedStatus {
+    pub kind: String,
+    pub url: String,
+    pub configured: bool,
+    pub webhooks: Vec<String>,
+    pub saved: Option<FeedRecord>,
+}
+
+impl FeedStatus {
+    fn id(&self) -> Option<i64> {
+        self.saved.as_ref().map(|r| r.id)
+    }
+
+    fn state(&self) -> &'static str {
+        match (&self.saved, self.configured) {
+            (_, false) => "not configured",
+            (None, true) => "not fetched",
+            (Some(r), true) if r.limit_exceeded.is_some() => "limit exceeded",
+            (Some(r), true) if r.is_new => "new",
+            (Some(_), true) => "ok",
+        }
+    }
+}
+
+/// Returns the status of every configured and every saved feed
+async fn feed_statuses(config: &Config, db: &Pool<Sqlite>) -> Result<Vec<FeedStatus>> {
+    let mut saved = Feeds::list(db).await?;
+
+    let mut statuses: Vec<FeedStatus> = config
+        .feeds
+        .iter()
+        .map(|f| FeedStatus {
+            kind: f.kind.clone(),
+            url: f.url.clone(),
+            configured: true,
+            webhooks: config
+                .feed_webhook_urls(f)
+                .unwrap_or_default()
+                .iter()
+                .map(|u| u.to_string())
+                .collect(),
+            saved: saved
+                .iter()
+                .position(|r| r.url == f.url)
+                .map(|i| saved.remove(i)),
+        })
+        .collect();
+    statuses.extend(saved.into_iter().map(|r| FeedStatus {
+        kind: r.kind.clone(),
+        url: r.url.clone(),
+        configured: false,
+        webhooks: vec![],
+        saved: Some(r),
+    }));
+
+    Ok(statuses)
+}
+
+/// Returns the feed with the url or id `feed`
+fn find_feed(statuses: Vec<FeedStatus>, feed: &str) -> Result<FeedStatus> {
+    statuses
+        .into_iter()
+        .find(|s| s.url == feed || s.id().map(|id| id.to_string()).as_deref() == Some(feed))
+        .ok_or_else(|| anyhow!("no feed with url or id '{}'", feed))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct FeedList(pub Vec<FeedStatus>);
+
+impl fmt::Display for FeedList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<6} {:<5} {:<8} {:<8} {:<15} URL",
+            "ID", "KIND", "ITEMS", "UNSENT", "STATE"
+        )?;
+        for status in &self.0 {
+            writeln!(
+                f,
+                "{:<6} {:<5} {:<8} {:<8} {:<15} {}",
+                or_dash(&status.id()),
+                status.kind,
+                or_dash(&status.saved.as_ref().map(|r| r.items)),
+                or_dash(&status.saved.as_ref().map(|r| r.unsent)),
+                status.state(),
+                status.url
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedDetails {
+    #[serde(flatten)]
+    pub feed: FeedStatus,
+    pub items: Vec<FeedItemRecord>,
+}
+
+impl fmt::Display for FeedDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let feed = &self.feed;
+        writeln!(f, "url:        {}", feed.url)?;
+        writeln!(f, "kind:       {}", feed.kind)?;
+        writeln!(f, "id:         {}", or_dash(&feed.id()))?;
+        writeln!(f, "state:      {}", feed.state())?;
+        writeln!(f, "webhooks:   {}", feed.webhooks.join(", "))?;
+        if let Some(saved) = &feed.saved {
+            writeln!(f, "items:      {}", saved.items)?;
+            writeln!(f, "unsent:     {}", saved.unsent)?;
+            if let Some(limit) = &saved.limit_exceeded {
+                writeln!(
+                    f,
+                    "limit:      {} (at {})",
+                    limit,
+                    or_dash(&saved.limit_exceeded_at)
+                )?;
+            }
+        }
+        if !self.items.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "{:<8} {:<20} {:<4} TITLE / LINK", "ID", "SAVED", "REV")?;
+            for item in &self.items {
+                writeln!(
+                    f,
+                    "{:<8} {:<20} {:<4} {}",
+                    item.id,
+                    or_dash(&item.created_at),
+                    item.revision,
+                    item.title.as_deref().map(html_to_line).unwrap_or_default()
+                )?;
+                writeln!(f, "{:<35}{}", "", item.link)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `Changed` reports a change made by a command
+#[derive(Debug, Serialize)]
+pub struct Changed {
+    pub action: &'static str,
+    pub target: String,
+    pub count: Option<u64>,
+}
+
+impl fmt::Display for Changed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.count {
+            Some(n) => writeln!(f, "{} {} {}", self.action, n, self.target),
+            None => writeln!(f, "{} {}", self.action, self.target),
+        }
+    }
+}
+
+pub async fn feeds(
+    config_path: &str,
+    command: &FeedsCommand,
+    output: OutputFormat,
+) -> Result<bool> {
+    let (config, db) = open(config_path).await?;
+
+    match command {
+        FeedsCommand::List => {
+            print_report(output, &FeedList(feed_statuses(&config, &db).await?))?;
+        }
+        FeedsCommand::Show { feed, items } => {
+            let feed = find_feed(feed_statuses(&config, &db).await?, feed)?;
+            let items = match feed.id() {
+                Some(id) => FeedItems::list(&db, id, *items).await?,
+                None => vec![],
+            };
+            print_report(output, &FeedDetails { feed, items })?;
+        }
+        FeedsCommand::Add {
+            kind,
+            url,
+            webhook_urls,
+            backfill,
+            notify_updates,
+        } => {
+            if !["rss", "atom"].contains(&kind.as_str()) {
+                return Err(anyhow!("unknown feed kind '{}'", kind));
+            }
+            url::Url::parse(url).with_context(|| format!("invalid feed url '{}'", url))?;
+            let feed = NewFeed {
+                kind: kind.clone(),
+                url: url.clone(),
+                webhook_urls: webhook_urls.clone(),
+                backfill: *backfill,
+                notify_updates: *notify_updates,
+                ..Default::default()
+            };
+            add_config_feeds(config_path, &[feed])?;
+            print_report(
+                output,
+                &Changed {
+                    action: "added feed",
+                    target: url.clone(),
+                    count: None,
+                },
+            )?;
+        }
+        FeedsCommand::Remove { feed, purge } => {
+            let feed = find_feed(feed_statuses(&config, &db).await?, feed)?;
+            if feed.configured {
+                remove_config_feed(config_path, &feed.url)?;
+                print_report(
+                    output,
+                    &Changed {
+                        action: "removed feed from config",
+                        target: feed.url.clone(),
+                        count: None,
+                    },
+                )?;
+            }
+            match (feed.id(), purge) {
+                (Some(id), true) => {
+                    Feeds::delete(&db, id).await?;
+                    print_report(
+                        output,
+                        &Changed {
+                            action: "deleted feed from database",
+                            target: feed.url.clone(),
+                            count: None,
+                        },
+                    )?;
+                }
+                (_, false) if !feed.configured => {
+                    return Err(anyhow!(
+                        "feed '{}' isn't configured; use --purge to delete it from the database",
+                        feed.url
+                    ));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    db.close().await;
+
+    Ok(true)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct NotificationList(pub Vec<NotificationRecord>);
+
+impl fmt::Display for NotificationList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<8} {:<8} {:<8} {:<6} {:<20} {:<20} TITLE / LINK",
+            "ID", "STATE", "WEBHOOK", "FEED", "CREATED", "SENT"
+        )?;
+        for n in &self.0 {
+            let title = n
+                .title
+                .as_deref()
+                .map(html_to_line)
+                .unwrap_or_else(|| n.link.clone());
+            writeln!(
+                f,
+                "{:<8} {:<8} {:<8} {:<6} {:<20} {:<20} {}",
+                n.id,
+                match (n.sent, n.replayed) {
+                    (true, _) => "sent",
+                    (false, true) => "replayed",
+                    (false, false) => "pending",
+                },
+                n.webhook_id,
+                n.feed_id,
+                or_dash(&n.created_at),
+                or_dash(&n.sent_at),
+                title
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `Retried` is the result of sending a notification again
+#[derive(Debug, Serialize)]
+pub struct Retried {
+    pub id: i64,
+    pub webhook: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct RetryReport(pub Vec<Retried>);
+
+impl fmt::Display for RetryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return writeln!(f, "no pending notifications");
+        }
+        for retried in &self.0 {
+            match &retried.error {
+                None => writeln!(f, "sent    {} to {}", retried.id, retried.webhook)?,
+                Some(e) => writeln!(f, "FAILED  {} to {}: {}", retried.id, retried.webhook, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn notifications(
+    config_path: &str,
+    command: &NotificationsCommand,
+    output: OutputFormat,
+) -> Result<bool> {
+    let (config, db) = open(config_path).await?;
+
+    let ok = match command {
+        NotificationsCommand::List {
+            webhook,
+            feed,
+            pending,
+            sent,
+            after,
+            before,
+            limit,
+        } => {
+            let filter = NotificationFilter {
+                webhook_id: *webhook,
+                feed_id: *feed,
+                sent: match (pending, sent) {
+                    (true, _) => Some(false),
+                    (_, true) => Some(true),
+                    _ => None,
+                },
+                after: *after,
+                before: *before,
+            };
+            let list = Notifications::list(&db, &filter, *limit).await?;
+            print_report(output, &NotificationList(list))?;
+            true
+        }
+        NotificationsCommand::Retry { ids, webhook, feed } => {
+            let client = HttpClient::from_config(&config)?;
+            let filter = NotificationFilter {
+                webhook_id: *webhook,
+                feed_id: *feed,
+                sent: Some(false),
+                ..Default::default()
+            };
+            let ids: HashSet<i64> = ids.iter().copied().collect();
+            let mut pending = Notifications::list(&db, &filter, -1).await?;
+            pending.retain(|n| ids.is_empty() || ids.contains(&n.id));
+            // Oldest first, like the poll loop
+            pending.reverse();
+
+            let mut report = vec![];
+            for record in pending {
+                let res = record.notification().send(&db, &client).await;
+                report.push(Retried {
+                    id: record.id,
+                    webhook: record.webhook,
+                    error: res.err().map(|e| error_chain(&e)),
+                });
+            }
+            let report = RetryReport(report);
+            print_report(output, &report)?;
+            report.0.iter().all(|r| r.error.is_none())
+        }
+        NotificationsCommand::Replay {
+            webhook,
+            feeds,
+            sent_after,
+            sent_before,
+            last,
+        } => {
+            let replay = Replay {
+                webhook: webhook.clone(),
+                feeds: feeds.clone(),
+                sent_after: *sent_after,
+                sent_before: *sent_before,
+                last: *last,
+            };
+            let n = replay.run(&db, &HttpClient::from_config(&config)?).await?;
+            print_report(
+                output,
+                &Changed {
+                    action: "queued",
+                    target: "notifications to be sent again".to_string(),
+                    count: Some(n),
+                },
+            )?;
+            true
+        }
+        NotificationsCommand::Purge {
+            webhook,
+            feed,
+            sent,
+            after,
+            before,
+        } => {
+            let filter = NotificationFilter {
+                webhook_id: *webhook,
+                feed_id: *feed,
+                sent: Some(*sent),
+                after: *after,
+                before: *before,
+            };
+            let n = Notifications::purge(&db, &filter).await?;
+            print_report(
+                output,
+                &Changed {
+                    action: "deleted",
+                    target: format!("{} notifications", if *sent { "sent" } else { "pending" }),
+                    count: Some(n),
+                },
+            )?;
+            true
+        }
+    };
+
+    db.close().await;
+
+    Ok(ok)
+}
+
+/// `Migration` is a migration and whether it was applied before or by
+/// `db migrate`
+#[derive(Debug, Serialize)]
+pub struct Migration {
+    pub version: i64,
+    pub description: String,
+    pub applied_now: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct MigrationReport(pub Vec<Migration>);
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for m in &self.0 {
+            writeln!(
+                f,
+                "{:<16} {:<8} {}",
+                m.version,
+                if m.applied_now { "applied" } else { "ok" },
+                m.description
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `VacuumReport` has the size of the database in bytes before and
+/// after `db vacuum`
+#[derive(Debug, Serialize)]
+pub struct VacuumReport {
+    pub size_before: i64,
+    pub size_after: i64,
+}
+
+impl fmt::Display for VacuumReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "vacuumed database: {} bytes before, {} bytes after",
+            self.size_before, self.size_after
+        )
+    }
+}
+
+async fn db_size(db: &Pool<Sqlite>) -> Result<i64> {
+    let page_count: i64 = sqlx::query_scalar("PRAGMA page_count")
+        .fetch_one(db)
+        .await?;
+    let page_size: i64 = sqlx::query_scalar("PRAGMA page_size").fetch_one(db).await?;
+
+    Ok(page_count * page_size)
+}
+
+pub async fn db(config_path: &str, command: &DbCommand, output: OutputFormat) -> Result<bool> {
+    let config = read_config_file(config_path.to_string())
+        .await
+        .context("failed to read config")?;
+    // Migrations are applied explicitly here
+    let db = connect_db(&config.db_path()).await?;
+
+    match command {
+        DbCommand::Migrate => {
+            // The migrations table doesn't exist in a new database
+            let applied = applied_migrations(&db).await.unwrap_or_default();
+            sqlx::migrate!("./migrations").run(&db).await?;
+            let report = sqlx::migrate!("./migrations")
+                .iter()
+                .map(|m| Migration {
+                    version: m.version,
+                    description: m.description.to_string(),
+                    applied_now: !applied.contains(&m.version),
+                })
+                .collect();
+            print_report(output, &MigrationReport(report))?;
+        }
+        DbCommand::Vacuum => {
+            let size_before = db_size(&db).await?;
+            sqlx::query("VACUUM").execute(&db).await?;
+            let size_after = db_size(&db).await?;
+            print_report(
+                output,
+                &VacuumReport {
+                    size_before,
+                    size_after,
+                },
+            )?;
+        }
+    }
+
+    db.close().await;
+
+    Ok(true)
+}
@@ -1,23 +1,205 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use toml_edit::{Array, DocumentMut, Item, Table};
+
+use crate::prelude::*;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    pub api_token: Option<Secret>,
     pub db_path: Option<String>,
+    pub dedup: Option<DedupConfig>,
     pub feeds: Vec<FeedConfig>,
+    pub health: Option<HealthConfig>,
+    pub http: Option<HttpConfig>,
+    pub limits: Option<LimitsConfig>,
+    pub links: Option<LinksConfig>,
+    pub listen: Option<String>,
+    pub network_policy: Option<NetworkPolicyConfig>,
     pub poll: Option<bool>,
     pub poll_sleep_dur: Option<u64>,
-    pub webhook_urls: Option<Vec<String>>,
+    pub shutdown_timeout: Option<u64>,
+    pub webhook_urls: Option<Vec<Secret>>,
+    pub webhooks: Option<Vec<WebhookConfig>>,
+}
+
+/// `DedupConfig` enables sending a story only once per webhook, even if
+/// it appears in several feeds or under different urls.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DedupConfig {
+    pub max_age: Option<u64>,
+    pub title_similarity: Option<f64>,
 }
 
+/// `LinksConfig` defines how item links are normalized, in addition to
+/// the removal of well-known tracking parameters.
 #[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct LinksConfig {
+    pub strip_params: Option<Vec<String>>,
+    pub resolve_redirects: Option<bool>,
+    pub redirect_hosts: Option<Vec<String>>,
+}
+
+impl Config {
+    pub fn db_path(&self) -> String {
+        self.db_path
+            .clone()
+            .unwrap_or_else(|| "sqlite://./feedcruncher.sqlite3".to_string())
+    }
+
+    /// Returns an error if the network policy can't be enforced: proxies
+    /// resolve host names themselves, so that only addresses in urls
+    /// could be checked
+    pub fn check_network_policy(&self) -> Result<()> {
+        let proxy = self
+            .http
+            .iter()
+            .chain(self.feeds.iter().filter_map(|f| f.http.as_ref()))
+            .any(|h| h.proxy.is_some());
+        if self.network_policy.is_some() && proxy {
+            return Err(anyhow!(
+                "`network_policy` can't be enforced for requests through a `proxy`"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the webhook named `name`
+    pub fn webhook_by_name(&self, name: &str) -> Option<&WebhookConfig> {
+        self.webhooks
+            .iter()
+            .flatten()
+            .find(|w| w.name.as_deref() == Some(name))
+    }
+
+    /// Returns the webhook urls of `feed`, either its own or the
+    /// global ones
+    pub fn feed_webhook_urls(&self, feed: &FeedConfig) -> Option<Vec<Secret>> {
+        feed.webhook_urls.clone().or(self.webhook_urls.clone())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 pub struct FeedConfig {
     pub kind: String,
     pub url: String,
-    pub webhook_urls: Option<Vec<String>>,
+    pub title: Option<String>,
+    pub category: Option<String>,
+    pub webhook_urls: Option<Vec<Secret>>,
+    pub backfill: Option<usize>,
+    pub notify_updates: Option<bool>,
+    pub http: Option<HttpConfig>,
+    pub limits: Option<LimitsConfig>,
+    pub auth: Option<AuthConfig>,
+    pub headers: Option<HashMap<String, Secret>>,
+}
+
+/// `AuthConfig` defines the credentials to fetch a feed with, either
+/// `username` and `password` for HTTP basic auth or a bearer `token`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    pub username: Option<Secret>,
+    pub password: Option<Secret>,
+    pub token: Option<Secret>,
+}
+
+/// `HealthConfig` defines when the daemon is considered unhealthy: if
+/// no poll cycle completed in `max_cycle_age` seconds or more than
+/// `max_unsent` notifications are waiting to be sent.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct HealthConfig {
+    pub max_cycle_age: Option<u64>,
+    pub max_unsent: Option<i64>,
+}
+
+/// `HttpConfig` configures the HTTP client; timeouts are in seconds.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct HttpConfig {
+    pub user_agent: Option<String>,
+    pub proxy: Option<Secret>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+    pub max_redirects: Option<usize>,
+    pub compression: Option<bool>,
+}
+
+impl HttpConfig {
+    /// Returns the config with the settings of `other` taking precedence
+    pub fn merge(&self, other: &HttpConfig) -> HttpConfig {
+        HttpConfig {
+            user_agent: other.user_agent.clone().or(self.user_agent.clone()),
+            proxy: other.proxy.clone().or(self.proxy.clone()),
+            connect_timeout: other.connect_timeout.or(self.connect_timeout),
+            read_timeout: other.read_timeout.or(self.read_timeout),
+            timeout: other.timeout.or(self.timeout),
+            max_redirects: other.max_redirects.or(self.max_redirects),
+            compression: other.compression.or(self.compression),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WebhookConfig {
+    pub url: Secret,
+    /// Name to refer to the webhook by, e.g. with `test-webhook`
+    pub name: Option<String>,
+    pub digest: Option<DigestConfig>,
+    pub delivery_windows: Option<Vec<DeliveryWindowConfig>>,
+}
+
+/// `DigestConfig` defines when a digest is sent, either every
+/// `interval` seconds or following a `cron` expression.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DigestConfig {
+    pub interval: Option<u64>,
+    pub cron: Option<String>,
+}
+
+/// `DeliveryWindowConfig` defines a time range, e.g. "08:00" to "18:00",
+/// on the given days in which notifications may be delivered.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DeliveryWindowConfig {
+    pub days: Option<Vec<String>>,
+    pub start: String,
+    pub end: String,
+    pub timezone: Option<String>,
+    pub digest: Option<bool>,
+}
+
+/// `LimitsConfig` defines limits for fetching a feed: the response size
+/// in bytes, the number of items and the time to parse in seconds.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct LimitsConfig {
+    pub max_response_size: Option<u64>,
+    pub max_items: Option<usize>,
+    pub parse_timeout: Option<u64>,
+}
+
+impl LimitsConfig {
+    /// Returns the config with the settings of `other` taking precedence
+    pub fn merge(&self, other: &LimitsConfig) -> LimitsConfig {
+        LimitsConfig {
+            max_response_size: other.max_response_size.or(self.max_response_size),
+            max_items: other.max_items.or(self.max_items),
+            parse_timeout: other.parse_timeout.or(self.parse_timeout),
+        }
+    }
+}
+
+/// `NetworkPolicyConfig` restricts the addresses feeds are fetched from
+/// and notifications are pushed to. `allow` and `deny` list hosts,
+/// addresses and networks in CIDR notation.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct NetworkPolicyConfig {
+    pub block_internal: Option<bool>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
 }
 
 pub async fn read_config_file(path: String) -> Result<Config> {
@@ -28,3 +210,162 @@ pub async fn read_config_file(path: String) -> Result<Config> {
 
     Ok(toml::from_str(&config_string)?)
 }
+
+/// `NewFeed` is a feed to add to the config file. Webhook urls are
+/// written as given, so they may reference environment variables with
+/// `${NAME}`.
+#[derive(Debug, Default, Serialize)]
+pub struct NewFeed {
+    pub kind: String,
+    pub url: String,
+    pub title: Option<String>,
+    pub category: Option<String>,
+    pub webhook_urls: Vec<String>,
+    pub backfill: Option<usize>,
+    pub notify_updates: bool,
+}
+
+/// Appends `feeds` to the feeds of the config file at `path`; comments
+/// and formatting of the rest of the file are kept
+pub fn add_config_feeds(path: &str, feeds: &[NewFeed]) -> Result<()> {
+    let mut doc = read_config_document(path)?;
+
+    let configured: Vec<String> = config_feeds(&doc)?
+        .iter()
+        .filter_map(|t| t.get("url").and_then(|u| u.as_str()))
+        .map(|u| u.to_string())
+        .collect();
+
+    let mut tables = vec![];
+    for feed in feeds {
+        if configured.contains(&feed.url) {
+            return Err(anyhow!("feed '{}' is configured already", feed.url));
+        }
+
+        let mut table = Table::new();
+        table["kind"] = toml_edit::value(&feed.kind);
+        table["url"] = toml_edit::value(&feed.url);
+        if let Some(title) = &feed.title {
+            table["title"] = toml_edit::value(title);
+        }
+        if let Some(category) = &feed.category {
+            table["category"] = toml_edit::value(category);
+        }
+        if !feed.webhook_urls.is_empty() {
+            let webhook_urls: Array = feed.webhook_urls.iter().collect();
+            table["webhook_urls"] = toml_edit::value(webhook_urls);
+        }
+        if let Some(backfill) = feed.backfill {
+            table["backfill"] = toml_edit::value(backfill as i64);
+        }
+        if feed.notify_updates {
+            table["notify_updates"] = toml_edit::value(true);
+        }
+        tables.push(table);
+    }
+
+    match doc.get_mut("feeds") {
+        Some(feeds) => feeds
+            .as_array_of_tables_mut()
+            .ok_or_else(|| anyhow!("`feeds` isn't an array of tables"))?
+            .extend(tables),
+        None => {
+            doc.insert("feeds", Item::ArrayOfTables(tables.into_iter().collect()));
+        }
+    }
+
+    write_config_document(path, &doc)
+}
+
+/// Removes the feed with `url` from the config file at `path`; returns
+/// false if there is no such feed
+pub fn remove_config_feed(path: &str, url: &str) -> Result<bool> {
+    let mut doc = read_config_document(path)?;
+
+    let feeds = match doc
+        .get_mut("feeds")
+        .and_then(|f| f.as_array_of_tables_mut())
+    {
+        Some(feeds) => feeds,
+        None => return Ok(false),
+    };
+    let before = feeds.len();
+    feeds.retain(|t| t.get("url").and_then(|u| u.as_str()) != Some(url));
+    if feeds.len() == before {
+        return Ok(false);
+    }
+
+    write_config_document(path, &doc)?;
+
+    Ok(true)
+}
+
+fn config_feeds(doc: &DocumentMut) -> Result<Vec<&Table>> {
+    match doc.get("feeds") {
+        Some(feeds) => Ok(feeds
+            .as_array_of_tables()
+            .ok_or_else(|| anyhow!("`feeds` isn't an array of tables"))?
+            .iter()
+            .collect()),
+        None => Ok(vec![]),
+    }
+}
+
+fn read_config_document(path: &str) -> Result<DocumentMut> {
+    let config_string = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file '{}'", path))?;
+
+    Ok(config_string.parse()?)
+}
+
+/// Writes `doc` to the config file at `path`. The file is replaced
+/// rather than changed in place so that a running daemon never reloads
+/// a partially written config. If `path` is a symlink, its target is
+/// replaced; the permissions of the file are kept.
+fn write_config_document(path: &str, doc: &DocumentMut) -> Result<()> {
+    let config_string = doc.to_string();
+
+    let target = std::fs::canonicalize(path)
+        .with_context(|| format!("failed to resolve config file '{}'", path))?;
+    let permissions = std::fs::metadata(&target)
+        .with_context(|| format!("failed to read config file '{}'", path))?
+        .permissions();
+    let file_name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    // In the same directory so that the rename doesn't cross file systems
+    let tmp_path = target.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        nanos
+    ));
+
+    let write = || -> Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        // Before writing, the config may hold credentials
+        file.set_permissions(permissions)?;
+        file.write_all(config_string.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.context(format!("failed to write '{}'", tmp_path.display())));
+    }
+    if let Err(e) = std::fs::rename(&tmp_path, &target) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e)
+            .with_context(|| format!("failed to replace config file '{}'", target.display()));
+    }
+
+    Ok(())
+}
@@ -1,16 +1,58 @@
 use anyhow::Result;
-use sqlx::sqlite::SqlitePool;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
 use sqlx::{Pool, Sqlite};
 
 pub async fn get_db_pool(db_path: &str) -> Result<Pool<Sqlite>> {
+    let pool = connect_db(db_path).await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await?;
+
+    Ok(pool)
+}
+
+/// Returns a pool for the database at `db_path` without running
+/// migrations
+pub async fn connect_db(db_path: &str) -> Result<Pool<Sqlite>> {
     // Set the Sqlite mode to read-write-create
     // to create the database if it doesn't exist
     // https://www.sqlite.org/c3ref/open.html
     let db_path = format!("{}?mode=rwc", db_path);
 
-    let pool = SqlitePool::connect(&db_path).await?;
+    Ok(SqlitePool::connect(&db_path).await?)
+}
+
+/// Returns a pool for a new, empty database in memory, e.g. to run the
+/// pipeline without changing the real database
+pub async fn get_memory_db_pool() -> Result<Pool<Sqlite>> {
+    // Every connection to `:memory:` opens its own database, so the
+    // pool must keep exactly one connection open
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .min_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await?;
 
     sqlx::migrate!("./migrations").run(&pool).await?;
 
     Ok(pool)
 }
+
+/// Returns the versions of the migrations applied to the database
+pub async fn applied_migrations(db: &Pool<Sqlite>) -> Result<Vec<i64>> {
+    Ok(
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = true")
+            .fetch_all(db)
+            .await?,
+    )
+}
+
+/// Returns true if all migrations have been applied to the database
+pub async fn migrations_applied(db: &Pool<Sqlite>) -> Result<bool> {
+    let applied = applied_migrations(db).await?;
+
+    Ok(sqlx::migrate!("./migrations")
+        .iter()
+        .all(|m| applied.contains(&m.version)))
+}
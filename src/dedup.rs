@@ -0,0 +1,255 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use sqlx::{Pool, Sqlite};
+use url::Url;
+
+use crate::prelude::*;
+
+/// `Dedup` drops notifications about stories that a webhook got
+/// already, recognized by their canonical link or, optionally, by
+/// a similar title.
+#[derive(Debug)]
+pub struct Dedup {
+    max_age: Duration,
+    title_similarity: Option<f64>,
+}
+
+struct Story {
+    link: String,
+    title: HashSet<String>,
+}
+
+impl Dedup {
+    pub fn from_config(config: &DedupConfig) -> Result<Self> {
+        if let Some(s) = config.title_similarity {
+            if !(0.0..=1.0).contains(&s) {
+                return Err(anyhow!(
+                    "title similarity must be between 0.0 and 1.0, got {}",
+                    s
+                ));
+            }
+        }
+
+        Ok(Self {
+            // Three days by default
+            max_age: Duration::seconds(config.max_age.unwrap_or(259200).try_into()?),
+            title_similarity: config.title_similarity,
+        })
+    }
+
+    /// Returns the notifications that aren't about a story sent to the
+    /// same webhook within `max_age` or earlier in `notifications`;
+    /// the others are marked as skipped
+    pub async fn filter(
+        &self,
+        db: &Pool<Sqlite>,
+        notifications: Vec<Notification>,
+    ) -> Result<Vec<Notification>> {
+        let mut stories: HashMap<i64, Vec<Story>> = HashMap::new();
+        let mut filtered = vec![];
+
+        for notification in notifications {
+            // Updates are about a story that was sent already, replays
+            // are meant to be sent again
+            if notification.revision > 0 || notification.replayed {
+                filtered.push(notification);
+                continue;
+            }
+
+            let seen = match stories.entry(notification.webhook_id) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    e.insert(self.recent_stories(db, notification.webhook_id).await?)
+                }
+            };
+
+            let item = FeedItems::get(db, notification.feed_item_id).await?;
+            let story = Story::new(&item.link, item.title.as_deref());
+
+            if seen.iter().any(|s| self.is_same(s, &story)) {
+                notification.mark_as_skipped(db).await?;
+                continue;
+            }
+
+            seen.push(story);
+            filtered.push(notification);
+        }
+
+        Ok(filtered)
+    }
+
+    /// Returns the stories delivered to the webhook within `max_age`;
+    /// items of a new feed's first fetch and skipped duplicates weren't
+    /// delivered
+    async fn recent_stories(&self, db: &Pool<Sqlite>, webhook_id: i64) -> Result<Vec<Story>> {
+        let since = (Utc::now() - self.max_age).naive_utc();
+
+        let result = sqlx::query!(
+            r#"
+            SELECT fi.link, fi.title
+            FROM notifications n
+            JOIN feed_items fi ON fi.id = n.feed_item_id
+            WHERE n.webhook_id = ? AND n.sent = true AND n.revision = 0
+                AND n.sent_at IS NOT NULL AND n.sent_at >= ?
+            "#,
+            webhook_id,
+            since,
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(result
+            .iter()
+            .map(|row| Story::new(&row.link, row.title.as_deref()))
+            .collect())
+    }
+
+    fn is_same(&self, a: &Story, b: &Story) -> bool {
+        if a.link == b.link {
+            return true;
+        }
+
+        match self.title_similarity {
+            Some(threshold) if !a.title.is_empty() && !b.title.is_empty() => {
+                let common = a.title.intersection(&b.title).count() as f64;
+                let all = a.title.union(&b.title).count() as f64;
+                common / all >= threshold
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Story {
+    fn new(link: &str, title: Option<&str>) -> Self {
+        Self {
+            link: canonical_url(link),
+            title: title
+                .unwrap_or_default()
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|w| !w.is_empty())
+                .map(|w| w.to_lowercase())
+                .collect(),
+        }
+    }
+}
+
+/// Returns `url` in a form that is the same for urls pointing to the
+/// same page: without scheme, `www.`, fragment, repeated and trailing
+/// slashes and tracking parameters, with sorted query parameters
+pub fn canonical_url(url: &str) -> String {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return url.to_string(),
+    };
+
+    let host = url.host_str().unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    let mut path = url.path().to_string();
+    while path.contains("//") {
+        path = path.replace("//", "/");
+    }
+    let path = path.trim_end_matches('/');
+
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !is_tracking_param(k))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    query.sort();
+
+    let mut canonical = format!("{}{}", host, path);
+    if let Some(port) = url.port() {
+        canonical = format!("{}:{}{}", host, port, path);
+    }
+    if !query.is_empty() {
+        let query: Vec<String> = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        canonical = format!("{}?{}", canonical, query.join("&"));
+    }
+
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedup(title_similarity: Option<f64>) -> Dedup {
+        Dedup::from_config(&DedupConfig {
+            max_age: None,
+            title_similarity,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn canonical_urls() {
+        let canonical = canonical_url("https://www.example.com/blog/post/");
+
+        assert_eq!(canonical, "example.com/blog/post");
+        assert_eq!(canonical_url("http://example.com/blog/post"), canonical);
+        assert_eq!(canonical_url("https://example.com/blog//post"), canonical);
+        assert_eq!(
+            canonical_url("https://example.com/blog/post?utm_source=rss&utm_medium=feed#comments"),
+            canonical
+        );
+        assert_eq!(
+            canonical_url("https://example.com/post?b=2&utm_campaign=x&a=1"),
+            canonical_url("https://example.com/post?a=1&b=2")
+        );
+
+        assert_ne!(
+            canonical_url("https://example.com/post?id=1"),
+            canonical_url("https://example.com/post?id=2")
+        );
+        assert_ne!(
+            canonical_url("https://example.com:8080/post"),
+            canonical_url("https://example.com/post")
+        );
+        assert_ne!(
+            canonical_url("https://blog.example.com/post"),
+            canonical_url("https://example.com/post")
+        );
+        assert_eq!(canonical_url("not a url"), "not a url");
+    }
+
+    #[test]
+    fn same_link() {
+        let a = Story::new("https://www.example.com/post/?utm_source=rss", Some("A"));
+        let b = Story::new("http://example.com/post", Some("B"));
+
+        assert!(dedup(None).is_same(&a, &b));
+    }
+
+    #[test]
+    fn title_similarity_threshold() {
+        // 3 of 6 distinct words are common: "rust", "2" and "0"
+        let a = Story::new("https://a.example/1", Some("Rust 2.0 released"));
+        let b = Story::new("https://b.example/2", Some("Rust 2.0 is out!"));
+
+        assert!(dedup(Some(0.5)).is_same(&a, &b));
+        assert!(!dedup(Some(0.51)).is_same(&a, &b));
+        assert!(!dedup(None).is_same(&a, &b));
+
+        // Case and punctuation don't matter
+        let c = Story::new("https://c.example/3", Some("rust: 2.0 RELEASED"));
+        assert!(dedup(Some(1.0)).is_same(&a, &c));
+
+        // Stories without title are only the same by link
+        let d = Story::new("https://d.example/4", None);
+        let e = Story::new("https://e.example/5", Some(""));
+        assert!(!dedup(Some(0.0)).is_same(&d, &e));
+    }
+
+    #[test]
+    fn title_similarity_must_be_a_share() {
+        assert!(Dedup::from_config(&DedupConfig {
+            max_age: None,
+            title_similarity: Some(1.5),
+        })
+        .is_err());
+    }
+}
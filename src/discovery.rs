@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use url::Url;
+
+use crate::prelude::*;
+
+/// How long a page without feeds isn't searched again
+const SEARCH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Paths that are tried if a page doesn't link any feeds
+const COMMON_PATHS: [&str; 3] = ["/feed", "/index.xml", "/atom.xml"];
+
+/// `Candidate` is a feed found for an HTML page
+#[derive(Clone, Debug, Serialize)]
+pub struct Candidate {
+    /// "rss", "atom" or "json"; JSON feeds are found but not supported
+    pub kind: String,
+    pub url: String,
+    pub title: Option<String>,
+}
+
+/// `Discovered` is what was found on the HTML page a feed url points at
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Discovered {
+    pub candidates: Vec<Candidate>,
+    /// The candidate that is fetched instead of the page
+    pub feed_url: Option<String>,
+}
+
+impl fmt::Display for Discovered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for candidate in &self.candidates {
+            write!(f, "        found {:<4} {}", candidate.kind, candidate.url)?;
+            if let Some(title) = &candidate.title {
+                write!(f, " \"{}\"", title)?;
+            }
+            match self.feed_url.as_ref() == Some(&candidate.url) {
+                true => writeln!(f, " (used)")?,
+                false => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `Discovery` remembers the feed found for a page, so that the page is
+/// only searched once, and pages without feed, so that they are only
+/// searched again after `SEARCH_INTERVAL`. Clones share what was found.
+#[derive(Clone, Debug, Default)]
+pub struct Discovery(Arc<Mutex<DiscoveryState>>);
+
+#[derive(Debug, Default)]
+struct DiscoveryState {
+    discovered: Discovered,
+    searched_at: Option<Instant>,
+}
+
+impl Discovery {
+    pub fn get(&self) -> Discovered {
+        self.0
+            .lock()
+            .map(|s| s.discovered.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, discovered: Discovered) {
+        if let Ok(mut s) = self.0.lock() {
+            s.discovered = discovered;
+            s.searched_at = Some(Instant::now());
+        }
+    }
+
+    /// Forgets the feed found, e.g. after it failed to be fetched
+    pub fn clear(&self) {
+        if let Ok(mut s) = self.0.lock() {
+            *s = DiscoveryState::default();
+        }
+    }
+
+    /// Returns true if the page was searched less than
+    /// `SEARCH_INTERVAL` ago without finding a feed
+    pub fn recently_searched(&self) -> bool {
+        self.0.lock().is_ok_and(|s| {
+            s.discovered.feed_url.is_none()
+                && s.searched_at.is_some_and(|t| t.elapsed() < SEARCH_INTERVAL)
+        })
+    }
+}
+
+/// Returns whether `document` is an HTML page rather than a feed
+pub fn is_html(document: &str, content_type: Option<&str>) -> bool {
+    if feed_kind(document).is_some() {
+        return false;
+    }
+
+    let content_type = content_type.unwrap_or_default().to_ascii_lowercase();
+    content_type.starts_with("text/html")
+        || content_type.starts_with("application/xhtml+xml")
+        || root_element(document).is_some_and(|e| e.eq_ignore_ascii_case("html"))
+}
+
+/// Returns the feeds of the HTML page `html` at `url`: the ones it links
+/// with `<link rel="alternate">` or, if there are none, the ones found
+/// at common paths of the site
+pub async fn discover(
+    client: &HttpClient,
+    url: &str,
+    html: &str,
+    limits: &Limits,
+) -> anyhow::Result<Vec<Candidate>> {
+    let base = Url::parse(url)?;
+    let candidates = feed_links(html, &base);
+    if !candidates.is_empty() {
+        return Ok(candidates);
+    }
+
+    let mut candidates = vec![];
+    for path in COMMON_PATHS {
+        let url = base.join(path)?.to_string();
+        if let Ok(kind) = detect_kind(client, &url, limits).await {
+            candidates.push(Candidate {
+                kind,
+                url,
+                title: None,
+            });
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Returns the first candidate of `kind`; pages usually link their main
+/// feed first
+pub fn best_candidate<'a>(candidates: &'a [Candidate], kind: &str) -> Option<&'a Candidate> {
+    candidates.iter().find(|c| c.kind == kind)
+}
+
+/// Returns the error for a page at `url` without feed of `kind`
+pub fn no_candidate(url: &str, kind: &str, candidates: &[Candidate]) -> Error {
+    if candidates.is_empty() {
+        return anyhow!("'{}' is an HTML page without feeds", redact_url(url));
+    }
+
+    let found: Vec<String> = candidates
+        .iter()
+        .map(|c| format!("{} {}", c.kind, redact_url(&c.url)))
+        .collect();
+    anyhow!(
+        "'{}' is an HTML page without {} feed, it links: {}",
+        redact_url(url),
+        kind,
+        found.join(", ")
+    )
+}
+
+/// Returns the feeds linked by `<link rel="alternate">` elements of
+/// `html`, in the order of the page
+fn feed_links(html: &str, base: &Url) -> Vec<Candidate> {
+    // Lowercasing ASCII keeps the byte offsets
+    let lowercase = html.to_ascii_lowercase();
+    let mut candidates: Vec<Candidate> = vec![];
+
+    let mut offset = 0;
+    while let Some(start) = lowercase[offset..].find("<link") {
+        let start = offset + start + "<link".len();
+        let end = match lowercase[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        offset = end;
+
+        let attributes = tag_attributes(&html[start..end]);
+        let alternate = attributes.get("rel").is_some_and(|rel| {
+            rel.split_whitespace()
+                .any(|r| r.eq_ignore_ascii_case("alternate"))
+        });
+        let kind = match attributes
+            .get("type")
+            .and_then(|t| t.split(';').next())
+            .map(|t| t.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("application/rss+xml") | Some("application/rdf+xml") => "rss",
+            Some("application/atom+xml") => "atom",
+            Some("application/feed+json") | Some("application/json") => "json",
+            _ => continue,
+        };
+        let url = match attributes.get("href").map(|h| base.join(h.trim())) {
+            Some(Ok(url)) => url.to_string(),
+            _ => continue,
+        };
+        if !alternate || candidates.iter().any(|c| c.url == url) {
+            continue;
+        }
+
+        candidates.push(Candidate {
+            kind: kind.to_string(),
+            url,
+            title: attributes.get("title").cloned(),
+        });
+    }
+
+    candidates
+}
+
+/// Returns the attributes of a tag, `tag` being the text between its
+/// name and `>`; names are lowercased
+fn tag_attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+
+    let mut rest = tag.trim_start();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('/') {
+            rest = r.trim_start();
+            continue;
+        }
+
+        let name_end = rest
+            .find(|c: char| c == '=' || c == '/' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let mut value = String::new();
+        if let Some(r) = rest.strip_prefix('=') {
+            let r = r.trim_start();
+            let (v, r) = match r.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let r = &r[1..];
+                    let end = r.find(quote).unwrap_or(r.len());
+                    (&r[..end], r.get(end + 1..).unwrap_or_default())
+                }
+                _ => {
+                    let end = r.find(char::is_whitespace).unwrap_or(r.len());
+                    r.split_at(end)
+                }
+            };
+            value = html_escape::decode_html_entities(v).to_string();
+            rest = r;
+        }
+
+        // A stray `=` has no name
+        if !name.is_empty() {
+            attributes.entry(name).or_insert(value);
+        }
+        rest = rest.trim_start();
+    }
+
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_quoting_and_entities() {
+        let attributes = tag_attributes(
+            r#" REL="alternate"  type='application/rss+xml' title="Tom &amp; Jerry's &quot;Blog&quot;" href=/feed.xml data-x = "a > b" hidden /"#,
+        );
+
+        assert_eq!(attributes["rel"], "alternate");
+        assert_eq!(attributes["type"], "application/rss+xml");
+        assert_eq!(attributes["title"], r#"Tom & Jerry's "Blog""#);
+        assert_eq!(attributes["href"], "/feed.xml");
+        assert_eq!(attributes["data-x"], "a > b");
+        assert_eq!(attributes["hidden"], "");
+    }
+
+    #[test]
+    fn attributes_first_one_wins() {
+        let attributes = tag_attributes(r#"href="/a" href="/b" = "stray""#);
+
+        assert_eq!(attributes["href"], "/a");
+        assert_eq!(attributes.len(), 1);
+    }
+
+    #[test]
+    fn links_of_page() {
+        let html = r#"<!DOCTYPE html>
+            <html><head>
+            <link rel="stylesheet" href="/style.css">
+            <LINK REL="Alternate" TYPE="application/atom+xml; charset=utf-8" href="atom.xml" title="Atom">
+            <link rel="alternate home" type="application/rss+xml" href="../rss.xml?a=1&amp;b=2">
+            <link rel="alternate" type="application/feed+json" href="https://cdn.example/feed.json">
+            <link rel="alternate" type="text/html" hreflang="de" href="/de/">
+            <link rel="me" type="application/rss+xml" href="/other.xml">
+            <link rel="alternate" type="application/rss+xml" href="../rss.xml?a=1&b=2">
+            </head></html>"#;
+        let base = Url::parse("https://example.com/blog/posts/").unwrap();
+
+        let found: Vec<(String, String)> = feed_links(html, &base)
+            .into_iter()
+            .map(|c| (c.kind, c.url))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "atom".to_string(),
+                    "https://example.com/blog/posts/atom.xml".to_string()
+                ),
+                (
+                    "rss".to_string(),
+                    "https://example.com/blog/rss.xml?a=1&b=2".to_string()
+                ),
+                (
+                    "json".to_string(),
+                    "https://cdn.example/feed.json".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn best_candidate_of_kind() {
+        let html = r#"<link rel="alternate" type="application/atom+xml" href="/atom.xml">
+            <link rel="alternate" type="application/rss+xml" href="/rss.xml">
+            <link rel="alternate" type="application/rss+xml" href="/comments.xml">"#;
+        let candidates = feed_links(html, &Url::parse("https://example.com/").unwrap());
+
+        assert_eq!(
+            best_candidate(&candidates, "rss").map(|c| c.url.as_str()),
+            Some("https://example.com/rss.xml")
+        );
+        assert!(best_candidate(&candidates, "json").is_none());
+    }
+
+    #[test]
+    fn html_pages() {
+        assert!(is_html("<!DOCTYPE html><html></html>", None));
+        assert!(is_html("<p>hi</p>", Some("text/html; charset=utf-8")));
+        // Feeds served as HTML
+        assert!(!is_html(
+            "<?xml version=\"1.0\"?><rss></rss>",
+            Some("text/html")
+        ));
+        assert!(!is_html(
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\"/>",
+            None
+        ));
+    }
+}
@@ -0,0 +1,149 @@
+use encoding_rs::{Encoding, UTF_8};
+
+/// Returns the XML document `body` decoded to UTF-8. The encoding is
+/// taken from the byte order mark, the XML declaration or the charset
+/// of the `Content-Type` header, in that order, and defaults to UTF-8.
+/// The declaration is rewritten to UTF-8 so that the XML parser doesn't
+/// decode the document a second time.
+pub fn decode_xml(body: &[u8], content_type: Option<&str>) -> String {
+    let (encoding, body) = match Encoding::for_bom(body) {
+        Some((encoding, bom_len)) => (encoding, &body[bom_len..]),
+        None => {
+            let encoding = xml_declaration_encoding(body)
+                .or_else(|| content_type.and_then(content_type_encoding))
+                .unwrap_or(UTF_8);
+            (encoding, body)
+        }
+    };
+
+    let (text, _) = encoding.decode_without_bom_handling(body);
+
+    match declaration(&text) {
+        Some(decl) => {
+            let utf8_decl = match attribute_value(decl, "encoding") {
+                Some(label) => decl.replacen(label, "UTF-8", 1),
+                None => decl.to_string(),
+            };
+            text.replacen(decl, &utf8_decl, 1)
+        }
+        None => text.into_owned(),
+    }
+}
+
+/// Returns the encoding named in the XML declaration, if any; the
+/// declaration is ASCII in all encodings that can be declared in it
+fn xml_declaration_encoding(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(1024)];
+    let head: String = head.iter().map(|b| *b as char).collect();
+
+    let label = attribute_value(declaration(&head)?, "encoding")?;
+
+    // A document that was decodable as ASCII can't be UTF-16
+    match Encoding::for_label(label.as_bytes())? {
+        e if e.output_encoding() == UTF_8 => Some(UTF_8),
+        e => Some(e),
+    }
+}
+
+fn content_type_encoding(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("charset") {
+            return None;
+        }
+        Encoding::for_label(value.trim().trim_matches('"').as_bytes())
+    })
+}
+
+/// Returns the XML declaration, e.g. `<?xml version="1.0"?>`, if the
+/// document starts with one
+fn declaration(text: &str) -> Option<&str> {
+    let text = text.trim_start();
+    if !text.starts_with("<?xml") {
+        return None;
+    }
+    let end = text.find("?>")?;
+
+    Some(&text[..end + 2])
+}
+
+fn attribute_value<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(name)? + name.len();
+    let rest = tag[start..].trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let rest = &rest[1..];
+    let end = rest.find(quote)?;
+
+    Some(&rest[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declared_latin1() {
+        let body = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><rss><title>Caf\xe9 \xbd</title></rss>";
+
+        assert_eq!(
+            decode_xml(body, None),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><rss><title>Café ½</title></rss>"
+        );
+    }
+
+    #[test]
+    fn utf16_bom() {
+        let text = "<?xml version=\"1.0\" encoding=\"UTF-16\"?><feed>Grüße</feed>";
+        let mut body = vec![0xff, 0xfe];
+        body.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes()));
+
+        // The BOM wins over the header
+        assert_eq!(
+            decode_xml(&body, Some("application/xml; charset=ISO-8859-1")),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><feed>Grüße</feed>"
+        );
+    }
+
+    #[test]
+    fn declaration_wins_over_header() {
+        let body = b"<?xml version='1.0' encoding='windows-1252'?><rss>\x93quoted\x94</rss>";
+
+        assert_eq!(
+            decode_xml(body, Some("text/xml; charset=\"utf-8\"")),
+            "<?xml version='1.0' encoding='UTF-8'?><rss>\u{201c}quoted\u{201d}</rss>"
+        );
+    }
+
+    #[test]
+    fn header_without_declaration() {
+        let body = b"<rss>Caf\xe9</rss>";
+
+        assert_eq!(
+            decode_xml(body, Some("application/rss+xml; charset=ISO-8859-1")),
+            "<rss>Café</rss>"
+        );
+        // Without any declared encoding, the document is UTF-8
+        assert_eq!(
+            decode_xml("<rss>Café</rss>".as_bytes(), None),
+            "<rss>Café</rss>"
+        );
+    }
+
+    #[test]
+    fn unknown_label() {
+        let body = "<?xml version=\"1.0\" encoding=\"x-unknown\"?><rss>Café</rss>".as_bytes();
+
+        // Falls back to the header and then to UTF-8
+        assert_eq!(
+            decode_xml(body, Some("text/xml; charset=bogus")),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><rss>Café</rss>"
+        );
+        assert_eq!(
+            decode_xml(
+                b"<?xml version=\"1.0\" encoding=\"x-unknown\"?><rss>Caf\xe9</rss>",
+                Some("text/xml; charset=latin1")
+            ),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><rss>Café</rss>"
+        );
+    }
+}
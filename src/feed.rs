@@ -1,11 +1,18 @@
 use core::fmt::Debug;
+use std::collections::HashSet;
 use std::fmt;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use sqlx::{Pool, Sqlite};
 use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::prelude::*;
 
 #[async_trait]
 pub trait Feed: Send + Sync + 'static {
@@ -50,6 +57,37 @@ pub trait Feed: Send + Sync + 'static {
         Ok(())
     }
 
+    /// Flags the feed if `e` is a `LimitExceeded` error and returns `e`
+    async fn check_limit_exceeded(&self, e: anyhow::Error) -> anyhow::Error {
+        if let Some(limit) = e.downcast_ref::<LimitExceeded>() {
+            if let Err(flag_err) = self.set_limit_exceeded(Some(limit.0.clone())).await {
+                return flag_err.context(e);
+            }
+        }
+        e
+    }
+
+    /// Saves which limit the feed exceeded during the last fetch, if any
+    async fn set_limit_exceeded(&self, limit: Option<String>) -> Result<()> {
+        let feed_id = self.id().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE feeds
+            SET limit_exceeded = ?,
+                limit_exceeded_at = CASE WHEN ? IS NULL THEN NULL ELSE CURRENT_TIMESTAMP END
+            WHERE id = ?
+            "#,
+            limit,
+            limit,
+            feed_id,
+        )
+        .execute(&self.db()?)
+        .await?;
+
+        Ok(())
+    }
+
     async fn id(&self) -> Result<i64> {
         let u = self.url();
 
@@ -86,7 +124,16 @@ pub trait Feed: Send + Sync + 'static {
         Ok(())
     }
 
-    fn webhook_urls(&self) -> Option<Vec<String>>;
+    fn webhook_urls(&self) -> Option<Vec<Secret>>;
+
+    /// Returns the feeds found if the url points at an HTML page
+    fn discovered(&self) -> Discovered;
+
+    /// Returns the url the feed document is fetched from: the feed found
+    /// if the url points at an HTML page, else the url
+    fn document_url(&self) -> String {
+        self.discovered().feed_url.unwrap_or_else(|| self.url())
+    }
 }
 
 impl Debug for dyn Feed {
@@ -95,19 +142,41 @@ impl Debug for dyn Feed {
     }
 }
 
+/// `FeedState` is what a feed learns while it's polled: the feed found
+/// if its url points at an HTML page and whether a parse still runs.
+/// It's kept when the config is reloaded; clones share the state.
+#[derive(Clone, Debug, Default)]
+pub struct FeedState {
+    pub discovery: Discovery,
+    pub parser: Parser,
+}
+
 #[derive(Debug, Clone)]
 pub struct RSSFeed {
     db: Pool<Sqlite>,
+    client: HttpClient,
+    limits: Limits,
     url: String,
-    webhook_urls: Option<Vec<String>>,
+    webhook_urls: Option<Vec<Secret>>,
+    state: FeedState,
 }
 
 impl RSSFeed {
-    pub fn new(url: String, webhook_urls: Option<Vec<String>>, db: &Pool<Sqlite>) -> Result<Self> {
+    pub fn new(
+        url: String,
+        webhook_urls: Option<Vec<Secret>>,
+        client: HttpClient,
+        limits: Limits,
+        db: &Pool<Sqlite>,
+        state: FeedState,
+    ) -> Result<Self> {
         Ok(Self {
             db: db.clone(),
+            client,
+            limits,
             url,
             webhook_urls,
+            state,
         })
     }
 }
@@ -126,17 +195,44 @@ impl Feed for RSSFeed {
         Ok(self.db.clone())
     }
 
-    fn webhook_urls(&self) -> Option<Vec<String>> {
+    fn webhook_urls(&self) -> Option<Vec<Secret>> {
         self.webhook_urls.clone()
     }
 
+    fn discovered(&self) -> Discovered {
+        self.state.discovery.get()
+    }
+
     async fn fetch(&self) -> Result<Vec<FeedItem>> {
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(15))
-            .build()?;
-        let response = client.get(&self.url).send().await?.text().await?;
-        let rssfeed = rss::Channel::read_from(response.as_bytes())
-            .with_context(|| format!("failed to parse RSS feed from '{}'", self.url))?;
+        let response = match fetch_feed(
+            &self.client,
+            &self.url,
+            &self.kind(),
+            &self.limits,
+            &self.state.discovery,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(self.check_limit_exceeded(e).await),
+        };
+        let parsed = self
+            .state
+            .parser
+            .parse(self.limits.parse_timeout, move || {
+                Ok(rss::Channel::read_from(response.as_bytes())?)
+            })
+            .await;
+        let rssfeed = match parsed {
+            Ok(rssfeed) => rssfeed,
+            Err(e) => {
+                let e = e.context(format!(
+                    "failed to parse RSS feed from '{}'",
+                    redact_url(&self.url)
+                ));
+                return Err(self.check_limit_exceeded(e).await);
+            }
+        };
 
         let feed: Arc<Mutex<Box<dyn Feed>>> =
             Arc::new(Mutex::new(Box::new(self.clone()) as Box<dyn Feed>));
@@ -146,41 +242,79 @@ impl Feed for RSSFeed {
             feed_guard.id().await?
         };
 
-        let items = rssfeed
-            .into_items()
+        let rssitems = rssfeed.into_items();
+        if rssitems.len() > self.limits.max_items {
+            warn!(
+                items = rssitems.len(),
+                max_items = self.limits.max_items,
+                "too many items, ignoring the rest"
+            );
+            self.set_limit_exceeded(Some(format!(
+                "{} items exceed maximum of {} items",
+                rssitems.len(),
+                self.limits.max_items
+            )))
+            .await?;
+        } else {
+            self.set_limit_exceeded(None).await?;
+        }
+
+        let items = rssitems
             .iter()
-            .map(|item| {
-                FeedItem {
+            .take(self.limits.max_items)
+            // Items without link can't be sent; the link identifies
+            // items without guid
+            .filter_map(|item| {
+                let link = item.link()?;
+                Some(FeedItem {
                     db: self.db.clone(),
-                    feed: feed.clone(),
+                    feed: Some(feed.clone()),
                     feed_id,
-                    // TODO: what if we receive malformed data w/o guid or link?
-                    // Is there a better way (w/o unwrap) to handle this?
-                    guid: item.guid().unwrap().value().to_string(),
-                    link: item.link().unwrap().to_string(),
+                    guid: item.guid().map(|g| g.value()).unwrap_or(link).to_string(),
+                    link: link.to_string(),
                     title: item.title().map(|s| s.to_string()),
+                    content: item.content().or(item.description()).map(|s| s.to_string()),
+                    summary: item
+                        .description()
+                        .or(item.content())
+                        .and_then(html_to_summary),
                     published_at: item.pub_date().map(|d| d.to_string()),
-                }
+                    updated_at: None,
+                    revision: 0,
+                })
             })
             .collect();
 
-        Ok(items)
+        Ok(unique_items(items))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct AtomFeed {
     db: Pool<Sqlite>,
+    client: HttpClient,
+    limits: Limits,
     url: String,
-    webhook_urls: Option<Vec<String>>,
+    webhook_urls: Option<Vec<Secret>>,
+    state: FeedState,
 }
 
 impl AtomFeed {
-    pub fn new(url: String, webhook_urls: Option<Vec<String>>, db: &Pool<Sqlite>) -> Result<Self> {
+    pub fn new(
+        url: String,
+        webhook_urls: Option<Vec<Secret>>,
+        client: HttpClient,
+        limits: Limits,
+        db: &Pool<Sqlite>,
+        state: FeedState,
+    ) -> Result<Self> {
         Ok(Self {
             db: db.clone(),
+            client,
+            limits,
             url,
             webhook_urls,
+            state,
         })
     }
 }
@@ -199,23 +333,42 @@ impl Feed for AtomFeed {
         Ok(self.db.clone())
     }
 
-    fn webhook_urls(&self) -> Option<Vec<String>> {
+    fn webhook_urls(&self) -> Option<Vec<Secret>> {
         self.webhook_urls.clone()
     }
 
+    fn discovered(&self) -> Discovered {
+        self.state.discovery.get()
+    }
+
     async fn fetch(&self) -> Result<Vec<FeedItem>> {
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(15))
-            .build()?;
-        let response = client.get(&self.url).send().await?.text().await?;
-        let atomfeed = match atom_syndication::Feed::read_from(response.as_bytes()) {
+        let response = match fetch_feed(
+            &self.client,
+            &self.url,
+            &self.kind(),
+            &self.limits,
+            &self.state.discovery,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(self.check_limit_exceeded(e).await),
+        };
+        let parsed = self
+            .state
+            .parser
+            .parse(self.limits.parse_timeout, move || {
+                Ok(atom_syndication::Feed::read_from(response.as_bytes())?)
+            })
+            .await;
+        let atomfeed = match parsed {
             Ok(feed) => feed,
             Err(e) => {
-                return Err(anyhow!(
-                    "failed to parse Atom feed from '{}': {}",
-                    self.url,
-                    e
+                let e = e.context(format!(
+                    "failed to parse Atom feed from '{}'",
+                    redact_url(&self.url)
                 ));
+                return Err(self.check_limit_exceeded(e).await);
             }
         };
 
@@ -227,32 +380,238 @@ impl Feed for AtomFeed {
             feed_guard.id().await?
         };
 
-        let items: Vec<FeedItem> = atomfeed
-            .entries()
+        let entries = atomfeed.entries();
+        if entries.len() > self.limits.max_items {
+            warn!(
+                entries = entries.len(),
+                max_items = self.limits.max_items,
+                "too many items, ignoring the rest"
+            );
+            self.set_limit_exceeded(Some(format!(
+                "{} entries exceed maximum of {} items",
+                entries.len(),
+                self.limits.max_items
+            )))
+            .await?;
+        } else {
+            self.set_limit_exceeded(None).await?;
+        }
+
+        let items = entries
             .iter()
-            .map(|entry| FeedItem {
-                db: self.db.clone(),
-                feed: feed.clone(),
-                feed_id,
-                guid: entry.id().to_string(),
-                link: entry.links().first().unwrap().href().to_string(),
-                title: Some(entry.title.value.clone()),
-                published_at: entry.published().map(|d| d.to_string()),
+            .take(self.limits.max_items)
+            // Entries without link can't be sent
+            .filter_map(|entry| {
+                let link = entry.links().first()?;
+                Some(FeedItem {
+                    db: self.db.clone(),
+                    feed: Some(feed.clone()),
+                    feed_id,
+                    guid: entry.id().to_string(),
+                    link: link.href().to_string(),
+                    title: Some(entry.title.value.clone()),
+                    content: entry
+                        .content()
+                        .and_then(|c| c.value())
+                        .or(entry.summary().map(|s| s.value.as_str()))
+                        .map(|s| s.to_string()),
+                    summary: entry
+                        .summary()
+                        .map(|s| s.value.as_str())
+                        .or(entry.content().and_then(|c| c.value()))
+                        .and_then(html_to_summary),
+                    published_at: entry.published().map(|d| d.to_string()),
+                    updated_at: Some(entry.updated().to_string()),
+                    revision: 0,
+                })
             })
             .collect();
 
-        Ok(items)
+        Ok(unique_items(items))
+    }
+}
+
+/// Returns `items` without the ones whose guid appeared before, e.g.
+/// entries that all link the homepage; they would otherwise overwrite
+/// each other when saved and look like an update on every fetch
+fn unique_items(items: Vec<FeedItem>) -> Vec<FeedItem> {
+    let mut guids = HashSet::new();
+
+    items
+        .into_iter()
+        .filter(|item| {
+            let unique = guids.insert(item.guid.clone());
+            if !unique {
+                debug!(item.guid, "ignoring item with the guid of a previous item");
+            }
+            unique
+        })
+        .collect()
+}
+
+/// `Page` is a fetched document, decoded to UTF-8
+struct Page {
+    status: reqwest::StatusCode,
+    content_type: Option<String>,
+    document: String,
+}
+
+async fn fetch_page(client: &HttpClient, url: &str, limits: &Limits) -> Result<Page> {
+    // Errors of reqwest contain the url, which may hold credentials
+    let response = client.get(url)?.send().await.map_err(|e| e.without_url())?;
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let body = read_body(response, limits.max_response_size).await?;
+
+    Ok(Page {
+        status,
+        document: decode_xml(&body, content_type.as_deref()),
+        content_type,
+    })
+}
+
+/// Returns the feed document of `kind` at `url`. If `url` is an HTML
+/// page, the best feed it links is fetched instead and remembered in
+/// `discovery`.
+async fn fetch_feed(
+    client: &HttpClient,
+    url: &str,
+    kind: &str,
+    limits: &Limits,
+    discovery: &Discovery,
+) -> Result<String> {
+    if let Some(feed_url) = discovery.get().feed_url {
+        // The site may have moved its feed; the page is searched again
+        // on the next fetch
+        return fetch_discovered(client, &feed_url, limits)
+            .await
+            .inspect_err(|_| discovery.clear());
+    }
+
+    let page = fetch_page(client, url, limits).await?;
+    if !page.status.is_success() || !is_html(&page.document, page.content_type.as_deref()) {
+        return Ok(page.document);
+    }
+    if discovery.recently_searched() {
+        return Err(no_candidate(url, kind, &discovery.get().candidates));
+    }
+
+    let candidates = discover(client, url, &page.document, limits).await?;
+    let feed_url = best_candidate(&candidates, kind).map(|c| c.url.clone());
+    discovery.set(Discovered {
+        candidates: candidates.clone(),
+        feed_url: feed_url.clone(),
+    });
+    let feed_url = feed_url.ok_or_else(|| no_candidate(url, kind, &candidates))?;
+    info!(
+        url = redact_url(url),
+        feed_url = redact_url(&feed_url),
+        "discovered feed on HTML page"
+    );
+
+    fetch_discovered(client, &feed_url, limits)
+        .await
+        .inspect_err(|_| discovery.clear())
+}
+
+/// Returns the document of a feed found on an HTML page
+async fn fetch_discovered(client: &HttpClient, feed_url: &str, limits: &Limits) -> Result<String> {
+    let page = fetch_page(client, feed_url, limits).await?;
+    if !page.status.is_success() {
+        return Err(anyhow!(
+            "'{}' returned {}",
+            redact_url(feed_url),
+            page.status
+        ));
+    }
+    if is_html(&page.document, page.content_type.as_deref()) {
+        return Err(anyhow!(
+            "'{}' is an HTML page, not a feed",
+            redact_url(feed_url)
+        ));
+    }
+
+    Ok(page.document)
+}
+
+/// Returns the kind of the feed at `url`, "rss" or "atom"
+pub async fn detect_kind(client: &HttpClient, url: &str, limits: &Limits) -> Result<String> {
+    let page = fetch_page(client, url, limits).await?;
+    if !page.status.is_success() {
+        return Err(anyhow!("'{}' returned {}", redact_url(url), page.status));
+    }
+
+    feed_kind(&page.document)
+        .map(|kind| kind.to_string())
+        .ok_or_else(|| anyhow!("'{}' is neither an RSS nor an Atom feed", redact_url(url)))
+}
+
+/// Returns the kind of feed `document` is by its root element: `rss`
+/// or, for RSS 1.0, `rdf:RDF` for RSS and `feed` for Atom
+pub fn feed_kind(document: &str) -> Option<&'static str> {
+    match root_element(document)?.rsplit(':').next() {
+        Some("rss") | Some("RDF") => Some("rss"),
+        Some("feed") => Some("atom"),
+        _ => None,
+    }
+}
+
+/// Returns the name of the root element of the XML or HTML `document`
+pub fn root_element(document: &str) -> Option<String> {
+    let mut rest = document;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        // The XML declaration, comments and the doctype
+        if rest.starts_with('?') || rest.starts_with('!') {
+            continue;
+        }
+        return Some(
+            rest.chars()
+                .take_while(|c| !c.is_whitespace() && *c != '>' && *c != '/')
+                .collect(),
+        );
     }
+
+    None
+}
+
+/// Returns the parsed publication date of an item; RSS uses RFC 2822
+/// dates, Atom dates are stored in chrono's default format
+pub fn parse_published(published_at: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc2822(published_at)
+        .or_else(|_| DateTime::parse_from_rfc3339(published_at))
+        .or_else(|_| DateTime::parse_from_str(published_at, "%Y-%m-%d %H:%M:%S%.f %:z"))
+        .ok()
+}
+
+/// `Saved` is the result of saving a feed item
+#[derive(Debug, PartialEq)]
+pub enum Saved {
+    New,
+    Unchanged,
+    /// The item changed and was saved with the new revision number
+    Revised(i64),
 }
 
 pub struct FeedItem {
     pub db: Pool<Sqlite>,
-    pub feed: Arc<Mutex<Box<dyn Feed>>>,
+    /// The feed the item was fetched from, `None` for items loaded from
+    /// the database or made up
+    pub feed: Option<Arc<Mutex<Box<dyn Feed>>>>,
     pub feed_id: i64,
     pub guid: String,
     pub link: String,
     pub title: Option<String>,
+    pub content: Option<String>,
+    /// The summary or, if there is none, the content as plain text
+    pub summary: Option<String>,
     pub published_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub revision: i64,
 }
 
 impl Debug for FeedItem {
@@ -262,24 +621,164 @@ impl Debug for FeedItem {
 }
 
 impl FeedItem {
-    pub async fn save(&self) -> Result<()> {
-        sqlx::query!(
+    /// Returns an item that isn't saved and belongs to no feed, e.g. to
+    /// test webhooks
+    pub fn synthetic(db: &Pool<Sqlite>, title: &str, link: &str) -> Self {
+        Self {
+            db: db.clone(),
+            feed: None,
+            feed_id: 0,
+            guid: link.to_string(),
+            link: link.to_string(),
+            title: Some(title.to_string()),
+            content: None,
+            summary: None,
+            published_at: None,
+            updated_at: None,
+            revision: 0,
+        }
+    }
+
+    /// Returns the parsed publication date
+    pub fn published(&self) -> Option<DateTime<FixedOffset>> {
+        parse_published(self.published_at.as_ref()?)
+    }
+
+    /// Returns the title to show in notifications as plain text;
+    /// revisions of already known items are marked as updated
+    pub fn notification_title(&self) -> Option<String> {
+        let title = self
+            .title
+            .as_deref()
+            .map(html_to_line)
+            .filter(|t| !t.is_empty());
+
+        match title {
+            Some(t) if self.revision > 0 => Some(format!("Updated: {}", t)),
+            Some(t) => Some(t),
+            None if self.revision > 0 => Some("Updated".to_string()),
+            None => None,
+        }
+    }
+
+    /// Returns the summary to show in notifications as plain text
+    pub fn notification_summary(&self) -> Option<&str> {
+        self.summary.as_deref().filter(|s| !s.is_empty())
+    }
+
+    /// Returns a hash over the parts of the item that can change
+    /// after publication
+    pub fn content_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        for part in [&self.title, &self.content, &self.updated_at] {
+            hasher.update(part.as_deref().unwrap_or_default());
+            hasher.update([0]);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Saves the item; if the item is known already and its content
+    /// changed, the previous version is saved as revision and the new
+    /// revision number is returned
+    pub async fn save(&self) -> Result<Saved> {
+        let content_hash = self.content_hash();
+
+        let inserted = sqlx::query!(
             r#"
-            INSERT INTO feed_items (feed_id, guid, link, title, published_at)
-            VALUES (?, ?, ?, ?, ?)
-            ON CONFLICT (guid, link) DO NOTHING
+            INSERT INTO feed_items (feed_id, guid, link, title, summary, published_at, updated_at, content_hash)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (feed_id, guid) DO NOTHING
             "#,
             self.feed_id,
             self.guid,
             self.link,
             self.title,
+            self.summary,
             self.published_at,
+            self.updated_at,
+            content_hash,
         )
         .execute(&self.db)
         .await
         .with_context(|| format!("guid: '{}' link: '{}'", self.guid, self.link))?;
+        if inserted.rows_affected() > 0 {
+            return Ok(Saved::New);
+        }
 
-        Ok(())
+        let saved = match sqlx::query!(
+            r#"
+            SELECT id, title, updated_at AS "updated_at: String", content_hash, revision
+            FROM feed_items
+            WHERE guid = ? AND feed_id = ?
+            "#,
+            self.guid,
+            self.feed_id,
+        )
+        .fetch_optional(&self.db)
+        .await?
+        {
+            Some(saved) => saved,
+            None => return Ok(Saved::Unchanged),
+        };
+
+        match saved.content_hash {
+            Some(h) if h == content_hash => return Ok(Saved::Unchanged),
+            Some(_) => (),
+            None => {
+                // Items saved before content hashes were introduced
+                sqlx::query!(
+                    r#"
+                    UPDATE feed_items
+                    SET content_hash = ?
+                    WHERE id = ?
+                    "#,
+                    content_hash,
+                    saved.id,
+                )
+                .execute(&self.db)
+                .await?;
+
+                return Ok(Saved::Unchanged);
+            }
+        }
+
+        let revision = saved.revision + 1;
+
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO feed_item_revisions (feed_item_id, revision, title, content_hash, updated_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+            saved.id,
+            saved.revision,
+            saved.title,
+            saved.content_hash,
+            saved.updated_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE feed_items
+            SET title = ?, summary = ?, updated_at = ?, content_hash = ?, revision = ?
+            WHERE id = ?
+            "#,
+            self.title,
+            self.summary,
+            self.updated_at,
+            content_hash,
+            revision,
+            saved.id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Saved::Revised(revision))
     }
 
     pub async fn id(&self) -> Result<i64> {
@@ -287,9 +786,10 @@ impl FeedItem {
             r#"
             SELECT id
             FROM feed_items
-            WHERE guid = ?
+            WHERE guid = ? AND feed_id = ?
             "#,
             self.guid,
+            self.feed_id,
         )
         .fetch_one(&self.db)
         .await?;
@@ -298,13 +798,33 @@ impl FeedItem {
     }
 }
 
+/// `FeedItemRecord` is a feed item as saved in the database
+#[derive(Debug, Serialize)]
+pub struct FeedItemRecord {
+    pub id: i64,
+    pub guid: String,
+    pub link: String,
+    pub title: Option<String>,
+    pub published_at: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub revision: i64,
+}
+
+impl FeedItemRecord {
+    /// Returns the parsed publication date
+    pub fn published(&self) -> Option<DateTime<FixedOffset>> {
+        parse_published(self.published_at.as_ref()?)
+    }
+}
+
 pub struct FeedItems {}
 
 impl FeedItems {
     pub async fn get(db: &Pool<Sqlite>, id: i64) -> Result<FeedItem> {
         let result = sqlx::query!(
             r#"
-            SELECT feed_id, guid, link, title, published_at AS "published_at: String"
+            SELECT feed_id, guid, link, title, summary, published_at AS "published_at: String",
+                updated_at AS "updated_at: String", revision
             FROM feed_items
             WHERE id = ?
             "#,
@@ -315,14 +835,169 @@ impl FeedItems {
 
         Ok(FeedItem {
             db: db.clone(),
-            feed: Arc::new(Mutex::new(
-                Box::new(RSSFeed::new("".to_string(), None, db)?) as Box<dyn Feed>,
-            )),
+            feed: None,
             feed_id: result.feed_id,
             guid: result.guid,
             link: result.link,
             title: result.title,
+            content: None,
+            summary: result.summary,
             published_at: result.published_at,
+            updated_at: result.updated_at,
+            revision: result.revision,
         })
     }
+
+    /// Returns the `limit` most recently saved items of the feed
+    pub async fn list(db: &Pool<Sqlite>, feed_id: i64, limit: i64) -> Result<Vec<FeedItemRecord>> {
+        let result = sqlx::query!(
+            r#"
+            SELECT id, guid, link, title, published_at AS "published_at: String",
+                created_at AS "created_at: NaiveDateTime", revision
+            FROM feed_items
+            WHERE feed_id = ?
+            ORDER BY id DESC
+            LIMIT ?
+            "#,
+            feed_id,
+            limit,
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(result
+            .into_iter()
+            .map(|row| FeedItemRecord {
+                id: row.id,
+                guid: row.guid,
+                link: row.link,
+                title: row.title,
+                published_at: row.published_at,
+                created_at: row.created_at,
+                revision: row.revision,
+            })
+            .collect())
+    }
+}
+
+/// `FeedRecord` is a feed as saved in the database, with the number of
+/// its items and unsent notifications
+#[derive(Debug, Serialize)]
+pub struct FeedRecord {
+    pub id: i64,
+    pub kind: String,
+    pub url: String,
+    pub is_new: bool,
+    pub items: i64,
+    pub unsent: i64,
+    pub limit_exceeded: Option<String>,
+    pub limit_exceeded_at: Option<NaiveDateTime>,
+}
+
+pub struct Feeds {}
+
+impl Feeds {
+    pub async fn list(db: &Pool<Sqlite>) -> Result<Vec<FeedRecord>> {
+        let result = sqlx::query!(
+            r#"
+            SELECT f.id, f.kind, f.url, f.is_new, f.limit_exceeded,
+                f.limit_exceeded_at AS "limit_exceeded_at: NaiveDateTime",
+                (SELECT COUNT(*) FROM feed_items fi WHERE fi.feed_id = f.id) AS "items!: i64",
+                (SELECT COUNT(*)
+                    FROM notifications n
+                    JOIN feed_items fi ON fi.id = n.feed_item_id
+                    WHERE fi.feed_id = f.id AND n.sent = false) AS "unsent!: i64"
+            FROM feeds f
+            ORDER BY f.id
+            "#,
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(result
+            .into_iter()
+            .map(|row| FeedRecord {
+                id: row.id,
+                kind: row.kind,
+                url: row.url,
+                is_new: row.is_new,
+                items: row.items,
+                unsent: row.unsent,
+                limit_exceeded: row.limit_exceeded,
+                limit_exceeded_at: row.limit_exceeded_at,
+            })
+            .collect())
+    }
+
+    /// Deletes the feed with its items, item revisions and
+    /// notifications
+    pub async fn delete(db: &Pool<Sqlite>, id: i64) -> Result<()> {
+        let mut tx = db.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM notifications
+            WHERE feed_item_id IN (SELECT id FROM feed_items WHERE feed_id = ?)
+            "#,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM feed_item_revisions
+            WHERE feed_item_id IN (SELECT id FROM feed_items WHERE feed_id = ?)
+            "#,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM feed_items
+            WHERE feed_id = ?
+            "#,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM feeds
+            WHERE id = ?
+            "#,
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn items_with_the_same_guid_are_ignored() {
+        let db = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let items = vec![
+            FeedItem::synthetic(&db, "First", "https://example.com/"),
+            FeedItem::synthetic(&db, "Post", "https://example.com/post"),
+            FeedItem::synthetic(&db, "Second", "https://example.com/"),
+        ];
+
+        let titles: Vec<Option<String>> =
+            unique_items(items).into_iter().map(|i| i.title).collect();
+        assert_eq!(
+            titles,
+            vec![Some("First".to_string()), Some("Post".to_string())]
+        );
+    }
 }
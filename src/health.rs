@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Sqlite};
+
+use crate::prelude::*;
+
+/// `Health` tracks the state of the poll loop for the health and
+/// readiness endpoints
+#[derive(Debug)]
+pub struct Health {
+    db: Pool<Sqlite>,
+    max_cycle_age: Duration,
+    max_unsent: i64,
+    /// Unix timestamp of the last completed poll cycle or, before the
+    /// first one completed, of the start
+    last_cycle: AtomicI64,
+    unsent: AtomicI64,
+}
+
+impl Health {
+    pub fn new(
+        config: Option<&HealthConfig>,
+        poll_sleep_dur: Duration,
+        db: &Pool<Sqlite>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        // A cycle takes the sleep plus the time to fetch and send
+        let max_cycle_age = match config.max_cycle_age {
+            Some(secs) => Duration::from_secs(secs),
+            None => poll_sleep_dur * 3,
+        };
+
+        Self {
+            db: db.clone(),
+            max_cycle_age,
+            max_unsent: config.max_unsent.unwrap_or(1000),
+            last_cycle: AtomicI64::new(now.timestamp()),
+            unsent: AtomicI64::new(0),
+        }
+    }
+
+    /// Records a completed poll cycle and the number of notifications
+    /// left unsent
+    pub fn cycle_completed(&self, now: DateTime<Utc>, unsent: i64) {
+        self.last_cycle.store(now.timestamp(), Ordering::Relaxed);
+        self.unsent.store(unsent, Ordering::Relaxed);
+    }
+
+    /// Returns an error if the poll loop hasn't completed a cycle in
+    /// time or too many notifications are waiting to be sent
+    pub fn check(&self, now: DateTime<Utc>) -> Result<()> {
+        let age = now.timestamp() - self.last_cycle.load(Ordering::Relaxed);
+        if age > self.max_cycle_age.as_secs() as i64 {
+            return Err(anyhow!("last poll cycle completed {} seconds ago", age));
+        }
+        let unsent = self.unsent.load(Ordering::Relaxed);
+        if unsent > self.max_unsent {
+            return Err(anyhow!(
+                "{} unsent notifications exceed maximum of {}",
+                unsent,
+                self.max_unsent
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns an error if the database can't be used
+    pub async fn check_ready(&self) -> Result<()> {
+        if self.db.is_closed() {
+            return Err(anyhow!("database is closed"));
+        }
+        if !migrations_applied(&self.db).await? {
+            return Err(anyhow!("database migrations are pending"));
+        }
+
+        Ok(())
+    }
+}
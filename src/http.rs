@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use base64::prelude::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use url::Url;
+
+use crate::prelude::*;
+
+const USER_AGENT: &str = concat!(
+    "feedcruncher/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/schu/feedcruncher)"
+);
+
+/// `HttpClient` is the HTTP client used to fetch feeds and to push
+/// notifications to webhooks. It's cheap to clone; clones share the
+/// connection pool.
+///
+/// All connections are subject to the network policy, also after
+/// redirects.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    policy: Arc<NetworkPolicy>,
+}
+
+impl HttpClient {
+    pub fn new(config: &HttpConfig, policy: &NetworkPolicy) -> Result<Self> {
+        Self::with_headers(config, policy, HeaderMap::new())
+    }
+
+    /// Returns a client that sends `headers` with every request; as they
+    /// may hold credentials, redirects to other origins aren't followed
+    pub fn with_headers(
+        config: &HttpConfig,
+        policy: &NetworkPolicy,
+        headers: HeaderMap,
+    ) -> Result<Self> {
+        let policy = Arc::new(policy.clone());
+        let max_redirects = config.max_redirects.unwrap_or(10);
+        let redirect_policy = policy.clone();
+        // reqwest only removes well-known sensitive headers on redirects
+        // to other hosts, not custom ones like `PRIVATE-TOKEN`
+        let same_origin_only = !headers.is_empty();
+
+        let mut builder = reqwest::Client::builder()
+            .default_headers(headers)
+            .user_agent(config.user_agent.as_deref().unwrap_or(USER_AGENT))
+            .connect_timeout(Duration::from_secs(config.connect_timeout.unwrap_or(10)))
+            .timeout(Duration::from_secs(config.timeout.unwrap_or(15)))
+            .dns_resolver(Arc::new(PolicyResolver {
+                policy: policy.clone(),
+            }))
+            // Redirects to addresses are checked here, redirects to
+            // host names when they are resolved
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                let origin = attempt.previous().first().map(|u| u.origin());
+                if attempt.previous().len() > max_redirects {
+                    attempt.error(anyhow!("too many redirects"))
+                } else if same_origin_only && origin != Some(attempt.url().origin()) {
+                    attempt.error(anyhow!(
+                        "not following redirect to another origin with credentials"
+                    ))
+                } else if let Err(e) = redirect_policy.check_url(attempt.url()) {
+                    attempt.error(e)
+                } else {
+                    attempt.follow()
+                }
+            }));
+
+        if let Some(t) = config.read_timeout {
+            builder = builder.read_timeout(Duration::from_secs(t));
+        }
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.expose())?);
+        }
+        if !config.compression.unwrap_or(true) {
+            builder = builder.gzip(false).brotli(false).deflate(false);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            policy,
+        })
+    }
+
+    /// Returns the client for the global `http` and `network_policy`
+    /// settings of `config`
+    pub fn from_config(config: &Config) -> Result<Self> {
+        config.check_network_policy()?;
+        let network_policy = NetworkPolicy::from_config(config.network_policy.as_ref())
+            .context("invalid network policy")?;
+
+        Self::new(&config.http.clone().unwrap_or_default(), &network_policy)
+            .context("invalid http config")
+    }
+
+    pub fn get(&self, url: &str) -> Result<reqwest::RequestBuilder> {
+        self.policy.check_url(&Url::parse(url)?)?;
+
+        Ok(self.client.get(url))
+    }
+
+    pub fn post(&self, url: &str) -> Result<reqwest::RequestBuilder> {
+        self.policy.check_url(&Url::parse(url)?)?;
+
+        Ok(self.client.post(url))
+    }
+
+    pub fn put(&self, url: &str) -> Result<reqwest::RequestBuilder> {
+        self.policy.check_url(&Url::parse(url)?)?;
+
+        Ok(self.client.put(url))
+    }
+}
+
+/// Returns the headers to fetch a feed with: its credentials and extra
+/// headers
+pub fn feed_headers(feed: &FeedConfig) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in feed.headers.iter().flatten() {
+        let mut value = HeaderValue::from_str(value.expose())?;
+        value.set_sensitive(true);
+        headers.insert(HeaderName::from_bytes(name.as_bytes())?, value);
+    }
+
+    let authorization = match &feed.auth {
+        Some(AuthConfig {
+            token: Some(token),
+            username: None,
+            password: None,
+        }) => format!("Bearer {}", token.expose()),
+        Some(AuthConfig {
+            token: None,
+            username: Some(username),
+            password,
+        }) => {
+            let password = match password {
+                Some(p) => p.expose(),
+                None => "",
+            };
+            let credentials = format!("{}:{}", username.expose(), password);
+            format!("Basic {}", BASE64_STANDARD.encode(credentials))
+        }
+        Some(_) => {
+            return Err(anyhow!(
+                "auth needs either `username` and `password` or `token` to be set"
+            ))
+        }
+        None => return Ok(headers),
+    };
+    let mut authorization = HeaderValue::from_str(&authorization)?;
+    authorization.set_sensitive(true);
+    headers.insert(AUTHORIZATION, authorization);
+
+    Ok(headers)
+}
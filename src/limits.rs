@@ -0,0 +1,149 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::prelude::*;
+
+/// `Limits` protect against feeds that are too large or take too
+/// long to parse, be it by accident or on purpose.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_response_size: u64,
+    pub max_items: usize,
+    pub parse_timeout: Duration,
+}
+
+impl Limits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            // 10 MiB
+            max_response_size: config.max_response_size.unwrap_or(10 * 1024 * 1024),
+            max_items: config.max_items.unwrap_or(1000),
+            parse_timeout: Duration::from_secs(config.parse_timeout.unwrap_or(10)),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new(&LimitsConfig::default())
+    }
+}
+
+/// `LimitExceeded` is the error returned if a feed exceeds one of its
+/// limits
+#[derive(Debug)]
+pub struct LimitExceeded(pub String);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "limit exceeded: {}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Returns the body of `response`; reading stops as soon as the body
+/// is larger than `max_size` bytes
+pub async fn read_body(mut response: reqwest::Response, max_size: u64) -> Result<Vec<u8>> {
+    if let Some(len) = response.content_length() {
+        if len > max_size {
+            return Err(LimitExceeded(format!(
+                "response size of {} bytes exceeds maximum of {} bytes",
+                len, max_size
+            ))
+            .into());
+        }
+    }
+
+    let mut body = vec![];
+    // Errors of reqwest contain the url, which may hold credentials
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.without_url())? {
+        if (body.len() + chunk.len()) as u64 > max_size {
+            return Err(LimitExceeded(format!(
+                "response exceeds maximum size of {} bytes",
+                max_size
+            ))
+            .into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// `Parser` parses the documents of a feed with a timeout, one at a
+/// time. A parser that times out runs on a blocking thread which can't
+/// be stopped; until it finishes, the feed isn't parsed again, so that a
+/// hostile feed ties up at most one thread. Clones share the state.
+#[derive(Clone, Debug, Default)]
+pub struct Parser {
+    running: Arc<AtomicBool>,
+}
+
+/// `Running` resets the running state of a `Parser` when the parse
+/// finishes, also if it panics
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Parser {
+    /// Returns the result of `parse` or an error if it takes longer
+    /// than `timeout` or a previous parse that timed out still runs
+    pub async fn parse<T, F>(&self, timeout: Duration, parse: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(LimitExceeded(
+                "a previous parse that took too long is still running".to_string(),
+            )
+            .into());
+        }
+        let running = Running(self.running.clone());
+
+        let parse = move || {
+            let _running = running;
+            parse()
+        };
+        match tokio::time::timeout(timeout, tokio::task::spawn_blocking(parse)).await {
+            Ok(result) => result?,
+            Err(_) => Err(LimitExceeded(format!(
+                "parsing took longer than {} seconds",
+                timeout.as_secs()
+            ))
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_is_skipped_while_a_timed_out_parse_runs() {
+        let parser = Parser::default();
+        let timeout = Duration::from_millis(50);
+
+        let slow = parser
+            .parse(timeout, || {
+                std::thread::sleep(Duration::from_millis(300));
+                Ok(())
+            })
+            .await;
+        assert!(slow.is_err());
+        assert!(parser.parse(timeout, || Ok(())).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(parser.parse(timeout, || Ok(())).await.is_ok());
+    }
+}
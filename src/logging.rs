@@ -0,0 +1,31 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use tracing_subscriber::EnvFilter;
+
+/// `LogFormat` is the format log events are written in
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Sets up logging to stderr. The log level is `info` unless set with
+/// `RUST_LOG`, e.g. `RUST_LOG=feedcruncher=debug`.
+pub fn init_logging(format: LogFormat) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    }
+    .map_err(|e| anyhow!(e))
+}
+
+/// Returns the error with its chain of causes on a single line, e.g.
+/// for the `error` field of log events
+pub fn error_chain(e: &anyhow::Error) -> String {
+    format!("{:#}", e)
+}
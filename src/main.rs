@@ -4,164 +4,332 @@
 #![warn(rust_2018_idioms)]
 #![forbid(unsafe_code)]
 
+mod cli;
+mod commands;
 mod config;
 mod database;
+mod dedup;
+mod discovery;
+mod encoding;
 mod feed;
+mod health;
+mod http;
+mod limits;
+mod logging;
+mod metrics;
+mod network;
+mod normalize;
 mod notification;
+mod opml;
+mod preview;
+mod reload;
+mod replay;
+mod schedule;
+mod scheduler;
+mod secret;
+mod server;
+mod shutdown;
+mod text;
 mod webhook;
 
 mod prelude {
+    pub use crate::cli::*;
+    pub use crate::commands::*;
     pub use crate::config::*;
     pub use crate::database::*;
+    pub use crate::dedup::*;
+    pub use crate::discovery::*;
+    pub use crate::encoding::*;
     pub use crate::feed::*;
+    pub use crate::health::*;
+    pub use crate::http::*;
+    pub use crate::limits::*;
+    pub use crate::logging::*;
+    pub use crate::metrics::*;
+    pub use crate::network::*;
+    pub use crate::normalize::*;
     pub use crate::notification::*;
+    pub use crate::opml::*;
+    pub use crate::preview::*;
+    pub use crate::reload::*;
+    pub use crate::replay::*;
+    pub use crate::schedule::*;
+    pub use crate::scheduler::*;
+    pub use crate::secret::*;
+    pub use crate::server::*;
+    pub use crate::shutdown::*;
+    pub use crate::text::*;
     pub use crate::webhook::*;
 }
 
 use crate::prelude::*;
 
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Instant;
 use std::vec;
 
 use anyhow::{anyhow, Result};
+use chrono::Utc;
 use clap::Parser;
 use sqlx::{Pool, Sqlite};
-use tokio::sync::Mutex;
 use tokio::task::JoinSet;
-
-#[derive(Parser, Debug)]
-#[clap(version = "0.1.0")]
-struct Opts {
-    #[clap(short, long)]
-    config: String,
-}
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
-    let config: Config = match read_config_file(opts.config).await {
+    init_logging(opts.log_format)?;
+
+    let config = &opts.config;
+    let output = opts.output;
+    let res = match &opts.command {
+        None | Some(Command::Run) => run(config).await.map(|_| true),
+        Some(Command::Check) => check(config, output).await,
+        Some(Command::Preview {
+            feed,
+            kind,
+            url,
+            webhook_urls,
+        }) => {
+            preview(
+                config,
+                feed.as_deref(),
+                kind.as_deref(),
+                url.as_deref(),
+                webhook_urls,
+                output,
+            )
+            .await
+        }
+        Some(Command::TestWebhook { webhook, item }) => {
+            test_webhook(config, webhook, *item, output).await
+        }
+        Some(Command::Feeds(command)) => feeds(config, command, output).await,
+        Some(Command::Notifications(command)) => notifications(config, command, output).await,
+        Some(Command::Db(command)) => db(config, command, output).await,
+        Some(Command::Opml(command)) => opml(config, command, output).await,
+    };
+
+    match res {
+        Ok(true) => Ok(()),
+        Ok(false) => exit(1),
+        Err(e) => {
+            error!(error = error_chain(&e), "failed to run command");
+            exit(1);
+        }
+    }
+}
+
+/// Fetches feeds and sends notifications until stopped, or once if
+/// polling is disabled
+async fn run(config_path: &str) -> Result<()> {
+    let config: Config = match read_config_file(config_path.to_string()).await {
         Ok(s) => s,
         Err(e) => {
-            println!("failed to read config: {}", e);
+            error!(error = error_chain(&e), "failed to read config");
             exit(1);
         }
     };
 
-    println!("config: {:#?}", config);
+    debug!(?config, "read config");
 
-    let poll = config.poll.unwrap_or(true);
+    let db = get_db_pool(&config.db_path()).await?;
 
-    let poll_sleep_dur = if let Some(d) = config.poll_sleep_dur {
-        tokio::time::Duration::from_secs(d)
-    } else {
-        tokio::time::Duration::from_secs(600)
-    };
+    let mut shutdown = Shutdown::listen()?;
+    let mut reload = Reload::listen(config_path)?;
 
-    let db_path = if let Some(p) = config.db_path {
-        p
-    } else {
-        "sqlite://./feedcruncher.sqlite3".to_string()
-    };
+    // Settings of the listener require a restart, everything else is
+    // reloaded with the config
+    let listen = config.listen.clone();
+    let health_config = config.health.clone();
+    let api_token = config.api_token.clone();
 
-    let db = get_db_pool(&db_path).await?;
+    let mut scheduler = match Scheduler::load(config, &db).await {
+        Ok(scheduler) => scheduler,
+        Err(e) => {
+            error!(error = error_chain(&e), "invalid config");
+            exit(1);
+        }
+    };
 
-    // Create list of feed objects
-    let feeds = config.feeds.iter().map(|f| {
-        let webhook_urls = if let Some(v) = f.webhook_urls.clone() {
-            Some(v.clone())
-        } else {
-            config.webhook_urls.clone()
-        };
+    let metrics = Arc::new(Metrics::new());
+    let health = Arc::new(Health::new(
+        health_config.as_ref(),
+        scheduler.poll_sleep_dur,
+        &db,
+        Utc::now(),
+    ));
+    let api = api_token.map(|token| {
+        Arc::new(Api {
+            db: db.clone(),
+            client: scheduler.client.clone(),
+            token,
+        })
+    });
+    if let Some(listen) = &listen {
+        if let Err(e) = serve(listen, metrics.clone(), health.clone(), api) {
+            error!(listen, error = error_chain(&e), "failed to start listener");
+            exit(1);
+        }
+    }
 
-        match feed_from_config(&f.kind, &f.url, webhook_urls, &db.clone()) {
-            Ok(feed) => feed,
-            Err(err) => {
-                println!("{}", err);
-                exit(1);
-            }
+    'poll: loop {
+        if shutdown.is_requested() {
+            break;
         }
-    });
 
-    // Save feeds
-    let mut set = JoinSet::new();
-    for feed in feeds.clone() {
-        set.spawn(async move { feed.lock().await.save().await });
-    }
-    while let Some(res) = set.join_next().await {
-        match res {
-            Ok(Ok(_)) => (),
-            Ok(Err(e)) => {
-                println!("failed to save feed: {}", e);
-                exit(1);
-            }
-            Err(e) => {
-                println!("failed to join saving feeds: {}", e);
-                exit(1);
-            }
+        if reload.take_requested() {
+            reload_scheduler(&mut scheduler, config_path, &db).await;
         }
-    }
-    assert!(set.is_empty());
+        let Scheduler {
+            config,
+            poll,
+            poll_sleep_dur,
+            shutdown_timeout,
+            digest_schedules,
+            delivery_windows,
+            notify_updates,
+            dedup,
+            client,
+            link_normalizer,
+            feeds,
+            ..
+        } = &scheduler;
 
-    loop {
         // Fetch feeds
         let mut set = JoinSet::new();
         for feed in feeds.clone() {
             let feed = feed.clone();
-            set.spawn(async move { feed.lock().await.fetch().await });
+            let metrics = metrics.clone();
+            set.spawn(async move {
+                let feed = feed.lock().await;
+                let feed_id = feed.id().await.ok();
+                let span = info_span!(
+                    "fetch",
+                    feed.url = redact_url(&feed.url()),
+                    feed.id = feed_id
+                );
+
+                async {
+                    let start = Instant::now();
+                    let res = feed.fetch().await;
+                    let duration = start.elapsed();
+                    let duration_ms = duration.as_millis() as u64;
+                    metrics.fetched(
+                        &feed.url(),
+                        res.is_ok(),
+                        duration.as_secs_f64(),
+                        Utc::now().timestamp(),
+                    );
+                    match &res {
+                        Ok(items) => info!(items = items.len(), duration_ms, "fetched feed"),
+                        Err(e) => {
+                            warn!(error = error_chain(e), duration_ms, "failed to fetch feed")
+                        }
+                    }
+                    res
+                }
+                .instrument(span)
+                .await
+            });
         }
 
-        // Collect feed items from feeds
+        // Collect feed items from feeds; fetches are stopped on
+        // shutdown
         let mut items: Vec<FeedItem> = vec![];
-        while let Some(res) = set.join_next().await {
-            let fetched_items = match res {
-                Ok(Ok(items)) => items,
-                Ok(Err(e)) => {
-                    println!("failed to fetch feed: {}", e);
-                    continue;
-                }
-                Err(e) => {
-                    println!("failed to join fetching feeds: {}", e);
-                    exit(1);
+        let collect = async {
+            while let Some(res) = set.join_next().await {
+                match res {
+                    Ok(Ok(fetched_items)) => items.extend(fetched_items),
+                    // Logged when fetching
+                    Ok(Err(_)) => (),
+                    Err(e) => {
+                        error!(error = %e, "failed to join fetching feeds");
+                        exit(1);
+                    }
                 }
-            };
-            items.extend(fetched_items);
+            }
+        };
+        let stopped = tokio::select! {
+            _ = collect => false,
+            _ = shutdown.requested() => true,
+        };
+        if stopped {
+            set.shutdown().await;
+            break;
         }
         assert!(set.is_empty());
 
+        // Clean up item links before saving them
+        link_normalizer.normalize(&mut items).await;
+
+        // Find the items of new feeds that should be sent anyway
+        let backfill = match backfill_items(&items, &config.feeds).await {
+            Ok(backfill) => Arc::new(backfill),
+            Err(e) => {
+                error!(error = error_chain(&e), "failed to find items to backfill");
+                Arc::new(HashSet::new())
+            }
+        };
+
         // Save feed items, webhooks and notifications
         let mut set = JoinSet::new();
         for item in items {
             let db = db.clone();
+            let client = client.clone();
+            let backfill = backfill.contains(&(item.feed_id, item.guid.clone()));
+            let notify_updates = notify_updates.clone();
+            let metrics = metrics.clone();
+            let span = info_span!("save", feed.id = item.feed_id, item.guid = item.guid);
+
+            let save = async move {
+                let feed = match &item.feed {
+                    Some(feed) => feed.clone(),
+                    None => return Err(anyhow!("item '{}' wasn't fetched", item.guid)),
+                };
+                let notify_updates = notify_updates.contains(&feed.lock().await.url());
 
-            set.spawn(async move {
                 // Save feed item
-                match item.save().await {
-                    Ok(_) => (),
+                let revision = match item.save().await {
+                    Ok(Saved::New) => {
+                        metrics.new_item(&feed.lock().await.url());
+                        None
+                    }
+                    Ok(Saved::Unchanged) => None,
+                    Ok(Saved::Revised(revision)) => Some(revision),
                     Err(e) => {
                         return Err(anyhow!("failed to save item: {:?}", e));
                     }
                 };
 
                 // Get the webhooks for the feed
-                let webhooks: Vec<Box<dyn Webhook>> =
-                    if let Some(v) = item.feed.lock().await.webhook_urls() {
-                        v.iter()
-                            .filter_map(|url| match webhook_from_url(url.clone()) {
-                                Ok(h) => Some(h),
-                                Err(e) => {
-                                    println!("{}", e);
-                                    None
-                                }
-                            })
-                            .collect()
-                    } else {
+                let webhook_urls = match feed.lock().await.webhook_urls() {
+                    Some(urls) => urls,
+                    None => {
                         return Err(anyhow!(
                             "got no webhook urls for feed '{}'",
-                            item.feed.lock().await.url()
+                            redact_url(&feed.lock().await.url())
                         ));
-                    };
+                    }
+                };
+                let webhooks: Vec<Box<dyn Webhook>> = webhook_urls
+                    .iter()
+                    .filter_map(|url| match webhook_from_url(url.clone(), &client) {
+                        Ok(h) => Some(h),
+                        Err(e) => {
+                            error!(
+                                webhook.url = %url,
+                                error = error_chain(&e),
+                                "invalid webhook"
+                            );
+                            None
+                        }
+                    })
+                    .collect();
 
                 // Save webhooks and notifications
                 for webhook in webhooks {
@@ -186,21 +354,25 @@ async fn main() -> Result<()> {
                     let notification = Notification {
                         feed_item_id: item_id,
                         webhook_id,
+                        revision: 0,
+                        created_at: None,
+                        replayed: false,
                     };
 
                     // We don't want to send notifications for items
-                    // if a feed was just added ...
+                    // if a feed was just added, unless they're to be
+                    // backfilled ...
                     let sent = {
                         let res = {
-                            let feed = item.feed.lock().await;
+                            let feed = feed.lock().await;
                             feed.is_new().await
                         };
                         match res {
-                            Ok(is_new) => is_new,
+                            Ok(is_new) => is_new && !backfill,
                             Err(e) => {
                                 return Err(anyhow!(
                                     "failed to check if feed is new '{}': {}",
-                                    item.feed.lock().await.url(),
+                                    redact_url(&feed.lock().await.url()),
                                     e
                                 ));
                             }
@@ -219,28 +391,51 @@ async fn main() -> Result<()> {
                             ));
                         }
                     };
+
+                    // Items that changed since they were saved get
+                    // another notification if the feed wants that
+                    if let (Some(revision), true) = (revision, notify_updates) {
+                        let notification = Notification {
+                            feed_item_id: item_id,
+                            webhook_id,
+                            revision,
+                            created_at: None,
+                            replayed: false,
+                        };
+                        if let Err(e) = notification.save(&db.clone(), false).await {
+                            return Err(anyhow!(
+                                "failed to save update notification '{}': {}",
+                                item.guid,
+                                e
+                            ));
+                        }
+                    }
                 }
 
                 Ok(())
-            });
-        }
-        while let Some(res) = set.join_next().await {
-            match res {
-                Ok(Ok(_)) => (),
-                Ok(Err(e)) => {
-                    println!("{}", e);
+            };
+
+            set.spawn(
+                async move {
                     // This could happen if the feed item has a duplicate guid;
                     // we don't want to exit in this case and ignore this for now
+                    if let Err(e) = save.await {
+                        error!(error = error_chain(&e), "failed to save item");
+                    }
                 }
-                Err(e) => {
-                    println!("failed to join saving items: {}", e);
-                    exit(1);
-                }
+                .instrument(span),
+            );
+        }
+        while let Some(res) = set.join_next().await {
+            if let Err(e) = res {
+                error!(error = %e, "failed to join saving items");
+                exit(1);
             }
         }
         assert!(set.is_empty());
 
         // Set `feeds.is_new` to false now that we've fetched the feeds
+        let mut set = JoinSet::new();
         for feed in feeds.clone() {
             set.spawn(async move { feed.lock().await.set_is_new(false).await });
         }
@@ -248,65 +443,263 @@ async fn main() -> Result<()> {
             match res {
                 Ok(Ok(_)) => (),
                 Ok(Err(e)) => {
-                    println!("failed to set `feed.is_new` to false: {}", e);
+                    error!(
+                        error = error_chain(&e),
+                        "failed to set `feed.is_new` to false"
+                    );
                 }
                 Err(e) => {
-                    println!("failed to join setting `feed.is_new`: {}", e);
+                    error!(error = %e, "failed to join setting `feed.is_new`");
                     exit(1);
                 }
             }
         }
         assert!(set.is_empty());
 
-        // Finally, send pending notifications
+        if shutdown.is_requested() {
+            break;
+        }
 
-        let noficiations = Notifications::get_unsent(&db).await?;
+        // Finally, send pending notifications; notifications for
+        // webhooks in digest mode are held until the digest is due and
+        // notifications deferred by a delivery window are sent as digest
+        // if the window says so
 
+        let now = Utc::now();
+        let noficiations = Notifications::get_unsent(&db, delivery_windows, now).await?;
+
+        // Drop notifications about stories that were sent already
+        let noficiations = match &dedup {
+            Some(dedup) => dedup.filter(&db, noficiations).await?,
+            None => noficiations,
+        };
+
+        let mut set = JoinSet::new();
+        let mut digests: HashMap<i64, Digest> = HashMap::new();
+        let mut webhook_kinds: HashMap<i64, &'static str> = HashMap::new();
         for notification in noficiations {
             let db = db.clone();
 
-            set.spawn(async move { notification.send(&db).await });
+            let webhook = match Webhooks::get(&db, notification.webhook_id, client).await {
+                Ok(webhook) => webhook,
+                Err(e) => {
+                    error!(
+                        webhook.id = notification.webhook_id,
+                        error = error_chain(&e),
+                        "failed to get webhook"
+                    );
+                    continue;
+                }
+            };
+            webhook_kinds.insert(notification.webhook_id, webhook.kind());
+            if let Some(schedule) = digest_schedules.get(webhook.url().expose()) {
+                digests
+                    .entry(notification.webhook_id)
+                    .or_insert_with(|| Digest::new(notification.webhook_id, Some(schedule.clone())))
+                    .notifications
+                    .push(notification);
+                continue;
+            }
+            if let Some(windows) = delivery_windows.get(webhook.url().expose()) {
+                let created_at = notification.created_at.map(|t| t.and_utc());
+                if windows.is_deferred_digest(created_at, now) {
+                    digests
+                        .entry(notification.webhook_id)
+                        .or_insert_with(|| Digest::new(notification.webhook_id, None))
+                        .notifications
+                        .push(notification);
+                    continue;
+                }
+            }
+
+            let client = client.clone();
+            let metrics = metrics.clone();
+            let webhook_kind = webhook.kind();
+            let span = info_span!(
+                "send",
+                webhook.id = notification.webhook_id,
+                feed_item.id = notification.feed_item_id,
+                revision = notification.revision,
+            );
+
+            set.spawn(
+                async move {
+                    let start = Instant::now();
+                    let res = notification.send(&db, &client).await;
+                    let duration_ms = start.elapsed().as_millis() as u64;
+                    metrics.notified(webhook_kind, res.is_ok(), 1);
+                    match res {
+                        Ok(_) => info!(duration_ms, "sent notification"),
+                        Err(e) => {
+                            error!(
+                                error = error_chain(&e),
+                                duration_ms, "failed to send notification"
+                            )
+                        }
+                    }
+                }
+                .instrument(span),
+            );
         }
-        while let Some(res) = set.join_next().await {
-            match res {
-                Ok(Ok(_)) => (),
-                Ok(Err(e)) => {
-                    println!("failed to send notification: {}", e);
+        for digest in digests.into_values() {
+            if !digest.is_due(now) {
+                continue;
+            }
+
+            let db = db.clone();
+            let client = client.clone();
+            let metrics = metrics.clone();
+            let webhook_kind = webhook_kinds[&digest.webhook_id];
+            let n = digest.notifications.len() as u64;
+            let span = info_span!(
+                "send_digest",
+                webhook.id = digest.webhook_id,
+                items = digest.notifications.len(),
+            );
+
+            set.spawn(
+                async move {
+                    let start = Instant::now();
+                    let res = digest.send(&db, &client).await;
+                    let duration_ms = start.elapsed().as_millis() as u64;
+                    metrics.notified(webhook_kind, res.is_ok(), n);
+                    match res {
+                        Ok(_) => info!(duration_ms, "sent digest"),
+                        Err(e) => error!(
+                            error = error_chain(&e),
+                            duration_ms, "failed to send digest"
+                        ),
+                    }
                 }
-                Err(e) => {
-                    println!("failed to join sending notifications: {}", e);
+                .instrument(span),
+            );
+        }
+        // Sends in flight are finished on shutdown, as a send that is
+        // stopped after the push but before the notification is marked
+        // as sent would be repeated after a restart
+        let join = async {
+            while let Some(res) = set.join_next().await {
+                if let Err(e) = res {
+                    error!(error = %e, "failed to join sending notifications");
                     exit(1);
                 }
             }
+        };
+        let shutdown_timed_out = async {
+            shutdown.requested().await;
+            tokio::time::sleep(*shutdown_timeout).await;
+        };
+        let timed_out = tokio::select! {
+            _ = join => false,
+            _ = shutdown_timed_out => true,
+        };
+        if timed_out {
+            warn!(
+                notifications = set.len(),
+                "shutdown timeout exceeded, stopping notifications in flight"
+            );
+            set.shutdown().await;
+            break;
         }
         assert!(set.is_empty());
 
+        match Notifications::count_unsent(&db).await {
+            Ok(n) => {
+                metrics.set_unsent_notifications(n);
+                health.cycle_completed(Utc::now(), n);
+            }
+            Err(e) => error!(
+                error = error_chain(&e),
+                "failed to count unsent notifications"
+            ),
+        }
+
         if !poll {
             break;
         }
-        tokio::time::sleep(poll_sleep_dur).await;
+        // The config is reloaded right away, but feeds are only fetched
+        // again once the interval is over
+        let next_poll = tokio::time::Instant::now() + *poll_sleep_dur;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_poll) => break,
+                _ = shutdown.requested() => break 'poll,
+                _ = reload.requested() => {
+                    if reload.take_requested() {
+                        reload_scheduler(&mut scheduler, config_path, &db).await;
+                    }
+                }
+            }
+        }
     }
 
+    db.close().await;
+    info!("stopped");
+
     Ok(())
 }
 
-fn feed_from_config(
-    kind: &str,
-    url: &str,
-    webhook_urls: Option<Vec<String>>,
-    db: &Pool<Sqlite>,
-) -> Result<Arc<Mutex<Box<dyn Feed>>>> {
-    match kind {
-        "atom" => Ok(Arc::new(Mutex::new(Box::new(AtomFeed::new(
-            url.to_string(),
-            webhook_urls,
-            db,
-        )?)))),
-        "rss" => Ok(Arc::new(Mutex::new(Box::new(RSSFeed::new(
-            url.to_string(),
-            webhook_urls,
-            db,
-        )?)))),
-        _ => Err(anyhow!("unknown feed kind '{}' for feed '{}'", kind, url)),
+/// Returns the scheduler for the config file at `path` to replace
+/// `scheduler` with
+async fn reload_config(scheduler: &Scheduler, path: &str, db: &Pool<Sqlite>) -> Result<Scheduler> {
+    let config = read_config_file(path.to_string()).await?;
+    debug!(?config, "read config");
+
+    scheduler.reload(config, db).await
+}
+
+/// Replaces `scheduler` with the one for the config file at `path`; an
+/// invalid config is rejected and the current one kept
+async fn reload_scheduler(scheduler: &mut Scheduler, path: &str, db: &Pool<Sqlite>) {
+    match reload_config(scheduler, path, db).await {
+        Ok(s) => {
+            *scheduler = s;
+            info!("reloaded config");
+        }
+        Err(e) => error!(
+            error = error_chain(&e),
+            "failed to reload config, keeping the current one"
+        ),
+    }
+}
+
+/// Returns the feed ids and guids of the `backfill` newest items of
+/// feeds that are fetched for the first time
+async fn backfill_items(
+    items: &[FeedItem],
+    feed_configs: &[FeedConfig],
+) -> Result<HashSet<(i64, String)>> {
+    let mut items_by_feed: HashMap<i64, Vec<&FeedItem>> = HashMap::new();
+    for item in items {
+        items_by_feed.entry(item.feed_id).or_default().push(item);
     }
+
+    let mut backfill = HashSet::new();
+    for feed_items in items_by_feed.values_mut() {
+        let (url, is_new) = {
+            let feed = match &feed_items[0].feed {
+                Some(feed) => feed.lock().await,
+                None => continue,
+            };
+            (feed.url(), feed.is_new().await?)
+        };
+        let n = match feed_configs.iter().find(|f| f.url == url) {
+            Some(FeedConfig {
+                backfill: Some(n), ..
+            }) if is_new => *n,
+            _ => continue,
+        };
+
+        // Items without publication date are sorted last,
+        // otherwise the order of the feed is kept
+        feed_items.sort_by_key(|item| Reverse(item.published()));
+        backfill.extend(
+            feed_items
+                .iter()
+                .take(n)
+                .map(|item| (item.feed_id, item.guid.clone())),
+        );
+    }
+
+    Ok(backfill)
 }
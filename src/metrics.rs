@@ -0,0 +1,140 @@
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FeedLabels {
+    feed: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FetchLabels {
+    feed: String,
+    outcome: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NotificationLabels {
+    webhook_type: String,
+    outcome: String,
+}
+
+/// `Metrics` holds the metrics exposed in Prometheus format
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    fetches: Family<FetchLabels, Counter>,
+    fetch_duration: Family<FeedLabels, Histogram, fn() -> Histogram>,
+    new_items: Family<FeedLabels, Counter>,
+    last_successful_fetch: Family<FeedLabels, Gauge>,
+    notifications: Family<NotificationLabels, Counter>,
+    unsent_notifications: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("feedcruncher"),
+            fetches: Family::default(),
+            // 50ms to ~25s
+            fetch_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.05, 2.0, 10))
+            }),
+            new_items: Family::default(),
+            last_successful_fetch: Family::default(),
+            notifications: Family::default(),
+            unsent_notifications: Gauge::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "fetches",
+            "Feed fetches by outcome",
+            metrics.fetches.clone(),
+        );
+        registry.register(
+            "fetch_duration_seconds",
+            "Duration of feed fetches",
+            metrics.fetch_duration.clone(),
+        );
+        registry.register(
+            "new_items",
+            "New items found in feeds",
+            metrics.new_items.clone(),
+        );
+        registry.register(
+            "last_successful_fetch_timestamp_seconds",
+            "Time of the last successful fetch of a feed",
+            metrics.last_successful_fetch.clone(),
+        );
+        registry.register(
+            "notifications",
+            "Notifications sent by webhook type and outcome",
+            metrics.notifications.clone(),
+        );
+        registry.register(
+            "unsent_notifications",
+            "Notifications waiting to be sent",
+            metrics.unsent_notifications.clone(),
+        );
+
+        metrics
+    }
+
+    /// Records a fetch of the feed at `url` that took `duration` seconds
+    pub fn fetched(&self, url: &str, success: bool, duration: f64, now: i64) {
+        let outcome = if success { "success" } else { "error" };
+        self.fetches
+            .get_or_create(&FetchLabels {
+                feed: redact_url(url),
+                outcome: outcome.to_string(),
+            })
+            .inc();
+
+        let feed = FeedLabels {
+            feed: redact_url(url),
+        };
+        self.fetch_duration.get_or_create(&feed).observe(duration);
+        if success {
+            self.last_successful_fetch.get_or_create(&feed).set(now);
+        }
+    }
+
+    pub fn new_item(&self, url: &str) {
+        self.new_items
+            .get_or_create(&FeedLabels {
+                feed: redact_url(url),
+            })
+            .inc();
+    }
+
+    /// Records `n` notifications sent to a webhook of type
+    /// `webhook_type`, e.g. as a digest
+    pub fn notified(&self, webhook_type: &str, success: bool, n: u64) {
+        let outcome = if success { "sent" } else { "failed" };
+        self.notifications
+            .get_or_create(&NotificationLabels {
+                webhook_type: webhook_type.to_string(),
+                outcome: outcome.to_string(),
+            })
+            .inc_by(n);
+    }
+
+    pub fn set_unsent_notifications(&self, n: i64) {
+        self.unsent_notifications.set(n);
+    }
+
+    /// Returns the metrics in the OpenMetrics text format
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut buffer = String::new();
+        encode(&mut buffer, &self.registry)?;
+
+        Ok(buffer)
+    }
+}
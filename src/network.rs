@@ -0,0 +1,299 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use url::{Host, Url};
+
+use crate::prelude::*;
+
+/// `NetworkPolicy` decides which addresses feedcruncher may connect to
+/// when fetching feeds and pushing notifications. Without a policy
+/// configured, everything is allowed.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkPolicy {
+    block_internal: bool,
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+/// `Rule` matches either a host name or the addresses of a network
+#[derive(Clone, Debug)]
+enum Rule {
+    Host(String),
+    Net(IpNet),
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self> {
+        if let Ok(net) = IpNet::from_str(rule) {
+            return Ok(Rule::Net(net));
+        }
+        if let Ok(ip) = IpAddr::from_str(rule) {
+            return Ok(Rule::Net(IpNet::from(ip)));
+        }
+        if rule.is_empty() || rule.contains('/') || rule.contains(':') {
+            return Err(anyhow!(
+                "'{}' is neither a host, an address nor a network",
+                rule
+            ));
+        }
+        Ok(Rule::Host(rule.trim_end_matches('.').to_lowercase()))
+    }
+
+    fn matches(&self, host: &str, addr: IpAddr) -> bool {
+        match self {
+            Rule::Host(h) => h == host.trim_end_matches('.'),
+            Rule::Net(net) => net.contains(&addr),
+        }
+    }
+}
+
+impl NetworkPolicy {
+    pub fn from_config(config: Option<&NetworkPolicyConfig>) -> Result<Self> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Self::default()),
+        };
+        let parse = |rules: &Option<Vec<String>>| -> Result<Vec<Rule>> {
+            rules.iter().flatten().map(|r| Rule::parse(r)).collect()
+        };
+
+        Ok(Self {
+            block_internal: config.block_internal.unwrap_or(true),
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+        })
+    }
+
+    /// Returns true if connecting to `addr`, resolved from `host`, is
+    /// allowed. Denied hosts and networks always win, the allowlist is
+    /// for intentional internal targets.
+    pub fn is_allowed(&self, host: &str, addr: IpAddr) -> bool {
+        let host = host.to_lowercase();
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            IpAddr::V4(_) => addr,
+        };
+
+        if self.deny.iter().any(|r| r.matches(&host, addr)) {
+            return false;
+        }
+        if self.allow.iter().any(|r| r.matches(&host, addr)) {
+            return true;
+        }
+
+        !(self.block_internal && is_internal(addr))
+    }
+
+    /// Returns an error if `url` points to an address that isn't
+    /// allowed. Host names are checked once they are resolved, see
+    /// `PolicyResolver`.
+    pub fn check_url(&self, url: &Url) -> Result<()> {
+        let addr = match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            Some(Host::Domain(domain)) => {
+                if self
+                    .deny
+                    .iter()
+                    .any(|r| matches!(r, Rule::Host(h) if h == &domain.to_lowercase()))
+                {
+                    return Err(Blocked(domain.to_string()).into());
+                }
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        if !self.is_allowed(&addr.to_string(), addr) {
+            return Err(Blocked(addr.to_string()).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns true for addresses that aren't reachable from the internet:
+/// loopback, link-local (including cloud metadata endpoints), private
+/// (RFC 1918, unique local) and other special purpose addresses. IPv6
+/// addresses that embed an IPv4 address are checked by that address.
+fn is_internal(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => is_internal_v6(ip),
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Shared address space (RFC 6598), also used for metadata
+        // endpoints, e.g. 100.100.100.200
+        || (a == 100 && (64..128).contains(&b))
+        || a == 0
+        // IETF protocol assignments, e.g. DS-Lite
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved, including broadcast
+        || a >= 240
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let first = segments[0];
+    let embedded_v4 = |high: u16, low: u16| {
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        Ipv4Addr::new(a, b, c, d)
+    };
+
+    // IPv4-mapped, ::ffff:a.b.c.d
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_internal_v4(v4);
+    }
+    // IPv4-compatible, ::a.b.c.d (deprecated)
+    if segments[..6] == [0; 6] && !ip.is_loopback() && !ip.is_unspecified() {
+        return is_internal_v4(embedded_v4(segments[6], segments[7]));
+    }
+    // NAT64 well-known prefix, 64:ff9b::a.b.c.d
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return is_internal_v4(embedded_v4(segments[6], segments[7]));
+    }
+    // 6to4, 2002:aabb:ccdd::
+    if first == 0x2002 {
+        return is_internal_v4(embedded_v4(segments[1], segments[2]));
+    }
+
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Local-use NAT64 (RFC 8215), translated by the local network
+        || segments[..3] == [0x64, 0xff9b, 0x1]
+        // Unique local, e.g. fd00:ec2::254
+        || (first & 0xfe00) == 0xfc00
+        // Link-local
+        || (first & 0xffc0) == 0xfe80
+        // Site-local (deprecated)
+        || (first & 0xffc0) == 0xfec0
+}
+
+/// `Blocked` is the error returned for connections denied by the
+/// network policy
+#[derive(Debug)]
+pub struct Blocked(pub String);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is blocked by the network policy", self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// `PolicyResolver` resolves host names and drops the addresses the
+/// policy doesn't allow. As every connection, including those after a
+/// redirect, is made to a resolved address, a host can't be switched to
+/// an internal address between check and connect.
+pub struct PolicyResolver {
+    pub policy: Arc<NetworkPolicy>,
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|a| policy.is_allowed(&host, a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(Box::new(Blocked(host)) as Box<dyn std::error::Error + Send + Sync>);
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(addr: &str) -> bool {
+        is_internal(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn internal_v4() {
+        for addr in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+            "224.0.0.1",
+        ] {
+            assert!(internal(addr), "{} should be internal", addr);
+        }
+        for addr in ["1.1.1.1", "93.184.215.14", "198.20.0.1", "192.0.1.1"] {
+            assert!(!internal(addr), "{} should be public", addr);
+        }
+    }
+
+    #[test]
+    fn internal_v6() {
+        for addr in [
+            "::1",
+            "::",
+            "fd00:ec2::254",
+            "fe80::1",
+            "fec0::1",
+            "ff02::1",
+            "64:ff9b:1::1",
+        ] {
+            assert!(internal(addr), "{} should be internal", addr);
+        }
+        for addr in ["2606:4700:4700::1111", "2a00:1450:4001:80b::200e"] {
+            assert!(!internal(addr), "{} should be public", addr);
+        }
+    }
+
+    #[test]
+    fn internal_embedded_v4() {
+        for addr in [
+            // IPv4-mapped
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            // IPv4-compatible
+            "::10.0.0.1",
+            "::127.0.0.1",
+            // NAT64
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::192.168.0.1",
+            // 6to4
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+        ] {
+            assert!(internal(addr), "{} should be internal", addr);
+        }
+        for addr in ["::ffff:1.1.1.1", "64:ff9b::101:101", "2002:101:101::1"] {
+            assert!(!internal(addr), "{} should be public", addr);
+        }
+    }
+}
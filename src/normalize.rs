@@ -0,0 +1,301 @@
+use anyhow::Result;
+use sqlx::{Pool, Sqlite};
+use tracing::warn;
+use url::{form_urlencoded, Url};
+
+use crate::prelude::*;
+
+/// Query parameters that only serve to track readers
+const TRACKING_PARAMS: &[&str] = &[
+    "_hsenc",
+    "_hsmi",
+    "dclid",
+    "fbclid",
+    "gclid",
+    "igshid",
+    "mc_cid",
+    "mc_eid",
+    "mkt_tok",
+    "msclkid",
+    "oly_anon_id",
+    "oly_enc_id",
+    "vero_id",
+    "wt_mc",
+    "yclid",
+];
+
+/// Hosts of link shorteners and feed proxies that redirect to the
+/// actual article
+const REDIRECT_HOSTS: &[&str] = &[
+    "feedproxy.google.com",
+    "feeds.feedburner.com",
+    "feedburner.google.com",
+];
+
+pub fn is_tracking_param(name: &str) -> bool {
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name)
+}
+
+/// `LinkNormalizer` cleans up item links before they are saved: links
+/// are made absolute, tracking parameters are removed and, optionally,
+/// redirects through feed proxies are resolved.
+pub struct LinkNormalizer {
+    db: Pool<Sqlite>,
+    client: HttpClient,
+    strip_params: Vec<String>,
+    redirect_hosts: Option<Vec<String>>,
+}
+
+impl LinkNormalizer {
+    pub fn new(config: Option<&LinksConfig>, client: &HttpClient, db: &Pool<Sqlite>) -> Self {
+        let strip_params = config
+            .and_then(|c| c.strip_params.clone())
+            .unwrap_or_default();
+        let redirect_hosts = match config {
+            Some(LinksConfig {
+                resolve_redirects: Some(true),
+                redirect_hosts,
+                ..
+            }) => Some(
+                redirect_hosts
+                    .clone()
+                    .unwrap_or_else(|| REDIRECT_HOSTS.iter().map(|h| h.to_string()).collect()),
+            ),
+            _ => None,
+        };
+
+        Self {
+            db: db.clone(),
+            client: client.clone(),
+            strip_params,
+            redirect_hosts,
+        }
+    }
+
+    pub async fn normalize(&self, items: &mut [FeedItem]) {
+        for item in items.iter_mut() {
+            // Relative links are relative to the feed document, which
+            // isn't the configured url for feeds found on an HTML page
+            let feed_url = match &item.feed {
+                Some(feed) => feed.lock().await.document_url(),
+                None => String::new(),
+            };
+            item.link = self.normalize_link(&item.link, &feed_url).await;
+        }
+    }
+
+    /// Returns `link` resolved against `base`, with redirects resolved
+    /// and without tracking parameters; links that can't be parsed are
+    /// returned as they are
+    pub async fn normalize_link(&self, link: &str, base: &str) -> String {
+        let mut url = match resolve_link(link, base) {
+            Some(url) => url,
+            None => return link.to_string(),
+        };
+
+        if let Some(redirect_hosts) = &self.redirect_hosts {
+            let host = url.host_str().unwrap_or_default();
+            if redirect_hosts.iter().any(|h| h == host) {
+                match self.resolve_redirect(&url).await {
+                    Ok(target) => url = target,
+                    Err(e) => warn!(
+                        link = %url,
+                        error = error_chain(&e),
+                        "failed to resolve redirect"
+                    ),
+                }
+            }
+        }
+
+        strip_query_params(&mut url, &self.strip_params);
+
+        url.to_string()
+    }
+
+    /// Returns the target of a redirect, from the redirect cache if
+    /// it was resolved before
+    async fn resolve_redirect(&self, url: &Url) -> Result<Url> {
+        let u = url.as_str();
+
+        let cached = sqlx::query!(
+            r#"
+            SELECT target
+            FROM redirects
+            WHERE url = ?
+            "#,
+            u,
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        if let Some(cached) = cached {
+            return Ok(Url::parse(&cached.target)?);
+        }
+
+        // Error pages and outages must not be cached as the target
+        let response = self
+            .client
+            .get(url.as_str())?
+            .send()
+            .await?
+            .error_for_status()?;
+        let target = response.url().clone();
+        let t = target.as_str();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO redirects (url, target)
+            VALUES (?, ?)
+            ON CONFLICT (url) DO NOTHING
+            "#,
+            u,
+            t,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(target)
+    }
+}
+
+/// Returns `link` resolved against `base`, or `None` if it can't be
+/// parsed
+fn resolve_link(link: &str, base: &str) -> Option<Url> {
+    match Url::parse(base) {
+        Ok(base) => base.join(link).ok(),
+        Err(_) => Url::parse(link).ok(),
+    }
+}
+
+/// Removes tracking parameters and `strip_params` from the query of
+/// `url`. Parameters are removed from the raw query, so that the others
+/// keep their encoding.
+fn strip_query_params(url: &mut Url, strip_params: &[String]) {
+    let query = match url.query() {
+        Some(query) => query,
+        None => return,
+    };
+
+    let params: Vec<&str> = query.split('&').collect();
+    let kept: Vec<&str> = params
+        .iter()
+        .copied()
+        .filter(|param| {
+            let key = form_urlencoded::parse(param.as_bytes())
+                .next()
+                .map(|(k, _)| k.into_owned())
+                .unwrap_or_default();
+            !is_tracking_param(&key) && !strip_params.contains(&key)
+        })
+        .collect();
+    if kept.is_empty() {
+        url.set_query(None);
+    } else if kept.len() != params.len() {
+        let query = kept.join("&");
+        url.set_query(Some(&query));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(link: &str, base: &str, strip_params: &[&str]) -> String {
+        let strip_params: Vec<String> = strip_params.iter().map(|p| p.to_string()).collect();
+        let mut url = resolve_link(link, base).unwrap();
+        strip_query_params(&mut url, &strip_params);
+        url.to_string()
+    }
+
+    #[test]
+    fn relative_links() {
+        let base = "https://example.com/blog/feeds/all.xml";
+
+        assert_eq!(
+            clean("post.html", base, &[]),
+            "https://example.com/blog/feeds/post.html"
+        );
+        assert_eq!(
+            clean("/2024/post", base, &[]),
+            "https://example.com/2024/post"
+        );
+        assert_eq!(
+            clean("//cdn.example.com/x", base, &[]),
+            "https://cdn.example.com/x"
+        );
+        assert_eq!(
+            clean("https://other.example/a", base, &[]),
+            "https://other.example/a"
+        );
+        // Without a base only absolute links can be parsed
+        assert_eq!(
+            clean("https://example.com/a", "", &[]),
+            "https://example.com/a"
+        );
+        assert!(resolve_link("post.html", "").is_none());
+    }
+
+    #[test]
+    fn tracking_params_are_removed() {
+        assert_eq!(
+            clean(
+                "https://example.com/a?utm_source=rss&id=1&utm_medium=feed&fbclid=x",
+                "",
+                &[]
+            ),
+            "https://example.com/a?id=1"
+        );
+        assert_eq!(
+            clean("https://example.com/a?utm_source=rss#top", "", &[]),
+            "https://example.com/a#top"
+        );
+        // The other parameters keep their encoding
+        assert_eq!(
+            clean(
+                "https://example.com/search?q=a%20b%2Bc&tag=x+y&gclid=1",
+                "",
+                &[]
+            ),
+            "https://example.com/search?q=a%20b%2Bc&tag=x+y"
+        );
+        assert_eq!(
+            clean("https://example.com/a?q=a%2Fb&utm_x=1", "", &[]),
+            "https://example.com/a?q=a%2Fb"
+        );
+    }
+
+    #[test]
+    fn configured_params_are_removed() {
+        assert_eq!(
+            clean("https://example.com/a?ref=rss&page=2", "", &["ref"]),
+            "https://example.com/a?page=2"
+        );
+        assert_eq!(
+            clean("https://example.com/a?ref=rss", "", &["ref"]),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            clean("https://example.com/a?reference=1", "", &["ref"]),
+            "https://example.com/a?reference=1"
+        );
+    }
+
+    #[test]
+    fn paths_are_kept() {
+        assert_eq!(
+            clean(
+                "https://web.archive.org/web/2020/https://example.com/x",
+                "",
+                &[]
+            ),
+            "https://web.archive.org/web/2020/https://example.com/x"
+        );
+        assert_eq!(
+            clean("https://cdn.example.com/bucket//key", "", &[]),
+            "https://cdn.example.com/bucket//key"
+        );
+        assert_eq!(
+            clean("https://example.com/blog/post/", "", &[]),
+            "https://example.com/blog/post/"
+        );
+    }
+}
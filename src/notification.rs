@@ -1,24 +1,34 @@
 use crate::prelude::*;
 
+use std::collections::HashMap;
+
 use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
 use sqlx::{Pool, Sqlite};
 
 #[derive(Debug)]
 pub struct Notification {
     pub feed_item_id: i64,
     pub webhook_id: i64,
+    pub revision: i64,
+    pub created_at: Option<NaiveDateTime>,
+    /// Whether the notification was queued again by a replay; replayed
+    /// notifications aren't deduplicated
+    pub replayed: bool,
 }
 
 impl Notification {
     pub async fn save(&self, db: &Pool<Sqlite>, sent: bool) -> Result<()> {
         sqlx::query!(
             r#"
-            INSERT INTO notifications (feed_item_id, webhook_id, sent)
-            VALUES (?, ?, ?)
-            ON CONFLICT (feed_item_id, webhook_id) DO NOTHING
+            INSERT INTO notifications (feed_item_id, webhook_id, revision, sent, created_at)
+            VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP)
+            ON CONFLICT (feed_item_id, webhook_id, revision) DO NOTHING
             "#,
             self.feed_item_id,
             self.webhook_id,
+            self.revision,
             sent,
         )
         .execute(db)
@@ -32,10 +42,50 @@ impl Notification {
             r#"
             UPDATE notifications
             SET sent = true, sent_at = CURRENT_TIMESTAMP
-            WHERE feed_item_id = ? AND webhook_id = ?
+            WHERE feed_item_id = ? AND webhook_id = ? AND revision = ?
+            "#,
+            self.feed_item_id,
+            self.webhook_id,
+            self.revision,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the notification as sent without delivering it, e.g. because
+    /// it's about a story the webhook got already
+    pub async fn mark_as_skipped(&self, db: &Pool<Sqlite>) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE notifications
+            SET sent = true, sent_at = NULL
+            WHERE feed_item_id = ? AND webhook_id = ? AND revision = ?
+            "#,
+            self.feed_item_id,
+            self.webhook_id,
+            self.revision,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Saves the notification as unsent and replayed, also if it was
+    /// sent already, so that it's sent (again)
+    pub async fn requeue(&self, db: &Pool<Sqlite>) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO notifications (feed_item_id, webhook_id, revision, sent, replayed, created_at)
+            VALUES (?, ?, ?, false, true, CURRENT_TIMESTAMP)
+            ON CONFLICT (feed_item_id, webhook_id, revision)
+            DO UPDATE SET sent = false, sent_at = NULL, replayed = true, created_at = CURRENT_TIMESTAMP
             "#,
             self.feed_item_id,
             self.webhook_id,
+            self.revision,
         )
         .execute(db)
         .await?;
@@ -43,26 +93,86 @@ impl Notification {
         Ok(())
     }
 
-    pub async fn send(&self, db: &Pool<Sqlite>) -> Result<()> {
-        let feed_item = FeedItems::get(db, self.feed_item_id).await?;
-        let webhook = Webhooks::get(db, self.webhook_id).await?;
+    pub async fn send(&self, db: &Pool<Sqlite>, client: &HttpClient) -> Result<()> {
+        let feed_item = self.feed_item(db).await?;
+        let webhook = Webhooks::get(db, self.webhook_id, client).await?;
 
         webhook.push(feed_item).await?;
 
         self.mark_as_sent(db).await
     }
+
+    /// Returns the feed item the notification is about, with the
+    /// revision of the notification
+    pub async fn feed_item(&self, db: &Pool<Sqlite>) -> Result<FeedItem> {
+        let mut feed_item = FeedItems::get(db, self.feed_item_id).await?;
+        feed_item.revision = self.revision;
+
+        Ok(feed_item)
+    }
+}
+
+/// `NotificationRecord` is a notification as saved in the database,
+/// with the item it is about and the redacted url of its webhook
+#[derive(Debug, Serialize)]
+pub struct NotificationRecord {
+    pub id: i64,
+    pub feed_id: i64,
+    pub feed_item_id: i64,
+    pub webhook_id: i64,
+    pub webhook: String,
+    pub revision: i64,
+    pub sent: bool,
+    pub sent_at: Option<NaiveDateTime>,
+    pub created_at: Option<NaiveDateTime>,
+    pub replayed: bool,
+    pub title: Option<String>,
+    pub link: String,
+}
+
+impl NotificationRecord {
+    pub fn notification(&self) -> Notification {
+        Notification {
+            feed_item_id: self.feed_item_id,
+            webhook_id: self.webhook_id,
+            revision: self.revision,
+            created_at: self.created_at,
+            replayed: self.replayed,
+        }
+    }
+}
+
+/// `NotificationFilter` selects notifications by webhook, by feed, by
+/// whether they were sent and by when they were sent or, if unsent,
+/// created; unset fields match every notification
+#[derive(Debug, Default)]
+pub struct NotificationFilter {
+    pub webhook_id: Option<i64>,
+    pub feed_id: Option<i64>,
+    pub sent: Option<bool>,
+    pub after: Option<NaiveDateTime>,
+    pub before: Option<NaiveDateTime>,
 }
 
 #[derive(Debug)]
 pub struct Notifications {}
 
 impl Notifications {
-    pub async fn get_unsent(db: &Pool<Sqlite>) -> Result<Vec<Notification>> {
+    /// Returns all unsent notifications except those deferred because
+    /// their webhook has delivery windows of which none is open `now`
+    pub async fn get_unsent(
+        db: &Pool<Sqlite>,
+        delivery_windows: &HashMap<String, DeliveryWindows>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Notification>> {
         let result = sqlx::query!(
             r#"
-            SELECT feed_item_id, webhook_id
-            FROM notifications
-            WHERE sent = false
+            SELECT n.feed_item_id, n.webhook_id, n.revision,
+                n.created_at AS "created_at: NaiveDateTime", n.replayed AS "replayed: bool", w.url
+            FROM notifications n
+            JOIN webhooks w ON w.id = n.webhook_id
+            WHERE n.sent = false
+            ORDER BY n.id
             "#,
         )
         .fetch_all(db)
@@ -70,10 +180,212 @@ impl Notifications {
 
         Ok(result
             .iter()
+            .filter(|row| match delivery_windows.get(&row.url) {
+                Some(windows) => windows.is_open(now),
+                None => true,
+            })
             .map(|row| Notification {
                 feed_item_id: row.feed_item_id,
                 webhook_id: row.webhook_id,
+                revision: row.revision,
+                created_at: row.created_at,
+                replayed: row.replayed,
+            })
+            .collect())
+    }
+
+    pub async fn count_unsent(db: &Pool<Sqlite>) -> Result<i64> {
+        let result = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS count
+            FROM notifications
+            WHERE sent = false
+            "#,
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(result.count)
+    }
+
+    /// Returns the `limit` newest notifications matching `filter`; a
+    /// negative `limit` returns all of them
+    pub async fn list(
+        db: &Pool<Sqlite>,
+        filter: &NotificationFilter,
+        limit: i64,
+    ) -> Result<Vec<NotificationRecord>> {
+        let result = sqlx::query!(
+            r#"
+            SELECT n.id, n.feed_item_id, n.webhook_id, n.revision, n.sent AS "sent: bool",
+                n.sent_at AS "sent_at: NaiveDateTime", n.created_at AS "created_at: NaiveDateTime",
+                n.replayed AS "replayed: bool", w.url AS webhook_url, fi.feed_id, fi.title, fi.link
+            FROM notifications n
+            JOIN webhooks w ON w.id = n.webhook_id
+            JOIN feed_items fi ON fi.id = n.feed_item_id
+            WHERE (? IS NULL OR n.webhook_id = ?)
+                AND (? IS NULL OR fi.feed_id = ?)
+                AND (? IS NULL OR n.sent = ?)
+                AND (? IS NULL OR COALESCE(n.sent_at, n.created_at) >= ?)
+                AND (? IS NULL OR COALESCE(n.sent_at, n.created_at) < ?)
+            ORDER BY n.id DESC
+            LIMIT ?
+            "#,
+            filter.webhook_id,
+            filter.webhook_id,
+            filter.feed_id,
+            filter.feed_id,
+            filter.sent,
+            filter.sent,
+            filter.after,
+            filter.after,
+            filter.before,
+            filter.before,
+            limit,
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(result
+            .into_iter()
+            .map(|row| NotificationRecord {
+                id: row.id,
+                feed_id: row.feed_id,
+                feed_item_id: row.feed_item_id,
+                webhook_id: row.webhook_id,
+                webhook: Secret::new(row.webhook_url).to_string(),
+                revision: row.revision,
+                sent: row.sent,
+                sent_at: row.sent_at,
+                created_at: row.created_at,
+                replayed: row.replayed,
+                title: row.title,
+                link: row.link,
             })
             .collect())
     }
+
+    /// Deletes the notifications matching `filter` and returns how many
+    /// were deleted
+    pub async fn purge(db: &Pool<Sqlite>, filter: &NotificationFilter) -> Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM notifications
+            WHERE (? IS NULL OR webhook_id = ?)
+                AND (? IS NULL OR feed_item_id IN (SELECT id FROM feed_items WHERE feed_id = ?))
+                AND (? IS NULL OR sent = ?)
+                AND (? IS NULL OR COALESCE(sent_at, created_at) >= ?)
+                AND (? IS NULL OR COALESCE(sent_at, created_at) < ?)
+            "#,
+            filter.webhook_id,
+            filter.webhook_id,
+            filter.feed_id,
+            filter.feed_id,
+            filter.sent,
+            filter.sent,
+            filter.after,
+            filter.after,
+            filter.before,
+            filter.before,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Marks the notifications matching `filter` that were delivered as
+    /// unsent and replayed, so that they are sent again, and returns how
+    /// many. The
+    /// notifications saved as sent without sending them, for items of
+    /// new feeds, aren't delivered and not matched.
+    pub async fn requeue(db: &Pool<Sqlite>, filter: &NotificationFilter) -> Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE notifications
+            SET sent = false, sent_at = NULL, replayed = true, created_at = CURRENT_TIMESTAMP
+            WHERE sent = true AND sent_at IS NOT NULL
+                AND (? IS NULL OR webhook_id = ?)
+                AND (? IS NULL OR feed_item_id IN (SELECT id FROM feed_items WHERE feed_id = ?))
+                AND (? IS NULL OR sent_at >= ?)
+                AND (? IS NULL OR sent_at < ?)
+            "#,
+            filter.webhook_id,
+            filter.webhook_id,
+            filter.feed_id,
+            filter.feed_id,
+            filter.after,
+            filter.after,
+            filter.before,
+            filter.before,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+/// `Digest` holds pending notifications of a webhook that are sent as
+/// a single message once the schedule fires or, without a schedule,
+/// right away.
+#[derive(Debug)]
+pub struct Digest {
+    pub webhook_id: i64,
+    pub schedule: Option<Schedule>,
+    pub notifications: Vec<Notification>,
+}
+
+impl Digest {
+    pub fn new(webhook_id: i64, schedule: Option<Schedule>) -> Self {
+        Self {
+            webhook_id,
+            schedule,
+            notifications: vec![],
+        }
+    }
+
+    /// Returns true if the schedule fired since the oldest notification
+    /// of the digest was created
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        let schedule = match &self.schedule {
+            Some(schedule) => schedule,
+            None => return true,
+        };
+
+        let oldest = self
+            .notifications
+            .iter()
+            .map(|n| n.created_at)
+            .min()
+            .flatten();
+
+        match oldest {
+            Some(created_at) => schedule.is_due(created_at.and_utc(), now),
+            // Notifications created before digests were supported
+            // have no creation time; don't hold them back
+            None => !self.notifications.is_empty(),
+        }
+    }
+
+    pub async fn send(&self, db: &Pool<Sqlite>, client: &HttpClient) -> Result<()> {
+        let mut feed_items = vec![];
+        for notification in &self.notifications {
+            feed_items.push(notification.feed_item(db).await?);
+        }
+        let webhook = Webhooks::get(db, self.webhook_id, client).await?;
+
+        // Notifications are marked as sent message by message, so that
+        // messages that went out aren't sent again if a later one fails
+        let mut notifications = self.notifications.iter();
+        for message in webhook.render_digest(&feed_items)? {
+            webhook.deliver(message.payload).await?.error_for_status()?;
+
+            for notification in notifications.by_ref().take(message.items) {
+                notification.mark_as_sent(db).await?;
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use html_escape::encode_double_quoted_attribute as escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Decoder, Reader};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::prelude::*;
+
+/// Number of feeds fetched at the same time to detect their kinds
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+/// `Outline` is a feed of an OPML document
+#[derive(Debug, PartialEq)]
+pub struct Outline {
+    pub url: String,
+    pub title: Option<String>,
+    /// Names of the categories the feed is nested in, joined with "/"
+    pub category: Option<String>,
+    /// The `type` attribute; most readers set "rss" for Atom feeds too
+    pub kind: Option<String>,
+}
+
+/// Returns the feeds of the OPML `document`. Outlines without feed url
+/// are categories; if a feed isn't nested in any, the first of its
+/// `category` attribute is used.
+pub fn parse_opml(document: &str) -> Result<Vec<Outline>> {
+    let mut reader = Reader::from_str(document);
+    reader.config_mut().trim_text(true);
+
+    // The names of the open outlines, `None` for feeds
+    let mut open: Vec<Option<String>> = vec![];
+    let mut outlines = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.name().as_ref() == b"outline" => {
+                let attributes = attributes(&e, reader.decoder())?;
+                match outline(&attributes, &open) {
+                    Some(outline) => {
+                        outlines.push(outline);
+                        open.push(None);
+                    }
+                    None => open.push(attributes.get("text").or(attributes.get("title")).cloned()),
+                }
+            }
+            Event::Empty(e) if e.name().as_ref() == b"outline" => {
+                outlines.extend(outline(&attributes(&e, reader.decoder())?, &open));
+            }
+            Event::End(e) if e.name().as_ref() == b"outline" => {
+                open.pop();
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    Ok(outlines)
+}
+
+fn attributes(e: &BytesStart<'_>, decoder: Decoder) -> Result<HashMap<String, String>> {
+    e.attributes()
+        .map(|a| {
+            let a = a?;
+            Ok((
+                String::from_utf8_lossy(a.key.as_ref()).to_string(),
+                a.decode_and_unescape_value(decoder)?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Returns the feed of an outline, `None` if it's a category
+fn outline(attributes: &HashMap<String, String>, open: &[Option<String>]) -> Option<Outline> {
+    let url = attributes.get("xmlUrl").filter(|u| !u.is_empty())?;
+
+    let categories: Vec<&str> = open.iter().flatten().map(|c| c.as_str()).collect();
+    let category = match categories.is_empty() {
+        false => Some(categories.join("/")),
+        true => attributes
+            .get("category")
+            .and_then(|c| c.split(',').next())
+            .map(|c| c.trim().trim_matches('/').to_string()),
+    };
+
+    Some(Outline {
+        url: url.clone(),
+        title: attributes
+            .get("title")
+            .or(attributes.get("text"))
+            .filter(|t| !t.is_empty())
+            .cloned(),
+        category: category.filter(|c| !c.is_empty()),
+        kind: attributes.get("type").map(|t| t.to_lowercase()),
+    })
+}
+
+/// `Folder` is a category of feeds when rendering OPML
+#[derive(Default)]
+struct Folder<'a> {
+    name: String,
+    folders: Vec<Folder<'a>>,
+    feeds: Vec<&'a FeedConfig>,
+}
+
+impl<'a> Folder<'a> {
+    fn folder(&mut self, path: &str) -> &mut Folder<'a> {
+        let mut folder = self;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            let i = match folder.folders.iter().position(|f| f.name == name) {
+                Some(i) => i,
+                None => {
+                    folder.folders.push(Folder {
+                        name: name.to_string(),
+                        ..Default::default()
+                    });
+                    folder.folders.len() - 1
+                }
+            };
+            folder = &mut folder.folders[i];
+        }
+        folder
+    }
+
+    fn render(&self, depth: usize, opml: &mut String) {
+        let indent = "  ".repeat(depth);
+        for folder in &self.folders {
+            let name = escape(&folder.name);
+            opml.push_str(&format!(
+                "{}<outline text=\"{}\" title=\"{}\">\n",
+                indent, name, name
+            ));
+            folder.render(depth + 1, opml);
+            opml.push_str(&format!("{}</outline>\n", indent));
+        }
+        for feed in &self.feeds {
+            let title = escape(feed.title.as_deref().unwrap_or(&feed.url));
+            opml.push_str(&format!(
+                "{}<outline type=\"rss\" text=\"{}\" title=\"{}\" xmlUrl=\"{}\"/>\n",
+                indent,
+                title,
+                title,
+                escape(&feed.url)
+            ));
+        }
+    }
+}
+
+/// Returns `feeds` as OPML document, nested by category
+pub fn render_opml(feeds: &[FeedConfig]) -> String {
+    let mut root = Folder::default();
+    for feed in feeds {
+        root.folder(feed.category.as_deref().unwrap_or_default())
+            .feeds
+            .push(feed);
+    }
+
+    let mut opml = String::new();
+    opml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    opml.push_str("<opml version=\"2.0\">\n");
+    opml.push_str("  <head>\n");
+    opml.push_str("    <title>feedcruncher</title>\n");
+    opml.push_str(&format!(
+        "    <dateCreated>{}</dateCreated>\n",
+        Utc::now().to_rfc2822()
+    ));
+    opml.push_str("  </head>\n");
+    opml.push_str("  <body>\n");
+    root.render(2, &mut opml);
+    opml.push_str("  </body>\n");
+    opml.push_str("</opml>\n");
+
+    opml
+}
+
+/// `Imported` is the result of importing a feed of an OPML file
+#[derive(Debug, Serialize)]
+pub struct Imported {
+    pub url: String,
+    pub title: Option<String>,
+    pub category: Option<String>,
+    pub kind: Option<String>,
+    pub status: &'static str,
+    /// Why the kind couldn't be detected or the feed wasn't imported
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct ImportReport(pub Vec<Imported>);
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for imported in &self.0 {
+            write!(
+                f,
+                "{:<12} {:<5} {}",
+                imported.status,
+                imported.kind.as_deref().unwrap_or("-"),
+                imported.url
+            )?;
+            if let Some(category) = &imported.category {
+                write!(f, " [{}]", category)?;
+            }
+            match &imported.error {
+                Some(e) => writeln!(f, ": {}", e)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn opml(config_path: &str, command: &OpmlCommand, output: OutputFormat) -> Result<bool> {
+    match command {
+        OpmlCommand::Import { file, dry_run } => {
+            opml_import(config_path, file, *dry_run, output).await
+        }
+        OpmlCommand::Export => opml_export(config_path).await,
+    }
+}
+
+/// Adds the feeds of the OPML file at `file` to the config file. The
+/// kind of every feed is detected by fetching it; if that fails, the
+/// `type` of the outline is used if it's a known kind.
+async fn opml_import(
+    config_path: &str,
+    file: &str,
+    dry_run: bool,
+    output: OutputFormat,
+) -> Result<bool> {
+    let config = read_config_file(config_path.to_string())
+        .await
+        .context("failed to read config")?;
+    let document =
+        std::fs::read_to_string(file).with_context(|| format!("failed to read '{}'", file))?;
+    let outlines = parse_opml(&document).context("invalid OPML")?;
+
+    let client = HttpClient::from_config(&config)?;
+    let limits = Limits::new(&config.limits.clone().unwrap_or_default());
+
+    let fetches = Arc::new(Semaphore::new(MAX_CONCURRENT_FETCHES));
+    let mut set = JoinSet::new();
+    for (i, outline) in outlines.iter().enumerate() {
+        let client = client.clone();
+        let limits = limits.clone();
+        let url = outline.url.clone();
+        let fetches = fetches.clone();
+        set.spawn(async move {
+            let _permit = fetches.acquire().await;
+            (i, detect_kind(&client, &url, &limits).await)
+        });
+    }
+    let mut detected = HashMap::new();
+    while let Some(res) = set.join_next().await {
+        let (i, kind) = res?;
+        detected.insert(i, kind);
+    }
+
+    let mut report = vec![];
+    let mut feeds: Vec<NewFeed> = vec![];
+    for (i, outline) in outlines.into_iter().enumerate() {
+        let mut imported = Imported {
+            url: outline.url.clone(),
+            title: outline.title.clone(),
+            category: outline.category.clone(),
+            kind: None,
+            status: "added",
+            error: None,
+        };
+
+        if config.feeds.iter().any(|f| f.url == outline.url)
+            || feeds.iter().any(|f| f.url == outline.url)
+        {
+            imported.status = "exists";
+            report.push(imported);
+            continue;
+        }
+
+        match detected.remove(&i) {
+            Some(Ok(kind)) => imported.kind = Some(kind),
+            Some(Err(e)) => {
+                imported.error = Some(error_chain(&e));
+                match outline.kind.as_deref() {
+                    Some("rss") | Some("atom") => imported.kind = outline.kind.clone(),
+                    _ => imported.status = "failed",
+                }
+            }
+            None => imported.status = "failed",
+        }
+        if let (Some(kind), "added") = (&imported.kind, imported.status) {
+            feeds.push(NewFeed {
+                kind: kind.clone(),
+                url: outline.url,
+                title: outline.title,
+                category: outline.category,
+                ..Default::default()
+            });
+        }
+        report.push(imported);
+    }
+
+    if !dry_run && !feeds.is_empty() {
+        add_config_feeds(config_path, &feeds)?;
+    }
+
+    let report = ImportReport(report);
+    print_report(output, &report)?;
+
+    Ok(report.0.iter().all(|i| i.status != "failed"))
+}
+
+/// Prints the configured feeds as OPML document
+async fn opml_export(config_path: &str) -> Result<bool> {
+    let config = read_config_file(config_path.to_string())
+        .await
+        .context("failed to read config")?;
+
+    print!("{}", render_opml(&config.feeds));
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nested_categories() {
+        let opml = r#"<?xml version="1.0"?>
+            <opml version="1.0">
+              <head><title>Subscriptions</title></head>
+              <body>
+                <outline text="Tech">
+                  <outline text="Rust &amp; Co" title="Rust">
+                    <outline type="rss" text="This Week" xmlUrl="https://this-week-in-rust.org/rss.xml"/>
+                  </outline>
+                  <outline type="rss" text="LWN" title="LWN.net" xmlUrl="https://lwn.net/headlines/rss"/>
+                </outline>
+                <outline type="rss" text="Top" xmlUrl="https://example.com/feed"/>
+              </body>
+            </opml>"#;
+
+        assert_eq!(
+            parse_opml(opml).unwrap(),
+            vec![
+                Outline {
+                    url: "https://this-week-in-rust.org/rss.xml".to_string(),
+                    title: Some("This Week".to_string()),
+                    category: Some("Tech/Rust & Co".to_string()),
+                    kind: Some("rss".to_string()),
+                },
+                Outline {
+                    url: "https://lwn.net/headlines/rss".to_string(),
+                    title: Some("LWN.net".to_string()),
+                    category: Some("Tech".to_string()),
+                    kind: Some("rss".to_string()),
+                },
+                Outline {
+                    url: "https://example.com/feed".to_string(),
+                    title: Some("Top".to_string()),
+                    category: None,
+                    kind: Some("rss".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_category_attribute() {
+        let opml = r#"<opml version="2.0"><body>
+            <outline text="A" xmlUrl="https://a.example/feed" category="/News/World,/Other"/>
+            <outline text="Folder">
+              <outline text="B" xmlUrl="https://b.example/feed" category="/Ignored"/>
+            </outline>
+            </body></opml>"#;
+
+        let outlines = parse_opml(opml).unwrap();
+        assert_eq!(outlines[0].category.as_deref(), Some("News/World"));
+        // Nesting takes precedence
+        assert_eq!(outlines[1].category.as_deref(), Some("Folder"));
+        assert_eq!(outlines[0].kind, None);
+    }
+
+    #[test]
+    fn render_and_parse() {
+        let feeds = vec![
+            FeedConfig {
+                kind: "atom".to_string(),
+                url: "https://a.example/feed?x=1&y=2".to_string(),
+                title: Some("A \"quoted\" <title>".to_string()),
+                category: Some("Tech/Rust".to_string()),
+                ..Default::default()
+            },
+            FeedConfig {
+                kind: "rss".to_string(),
+                url: "https://b.example/rss".to_string(),
+                category: Some("Tech".to_string()),
+                ..Default::default()
+            },
+            FeedConfig {
+                kind: "rss".to_string(),
+                url: "https://c.example/rss".to_string(),
+                title: Some("C".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        let mut outlines = parse_opml(&render_opml(&feeds)).unwrap();
+        outlines.sort_by(|a, b| a.url.cmp(&b.url));
+        assert_eq!(outlines.len(), feeds.len());
+        for (outline, feed) in outlines.iter().zip(&feeds) {
+            assert_eq!(outline.url, feed.url);
+            assert_eq!(outline.category, feed.category);
+            assert_eq!(
+                outline.title.as_deref(),
+                Some(feed.title.as_deref().unwrap_or(&feed.url))
+            );
+        }
+    }
+}
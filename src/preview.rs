@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::prelude::*;
+
+/// `PreviewReport` is the result of `preview`: the items of a feed that
+/// would be saved and the payloads its webhooks would get
+#[derive(Debug, Serialize)]
+pub struct PreviewReport {
+    pub kind: String,
+    pub url: String,
+    /// Feeds found if `url` is an HTML page
+    #[serde(flatten)]
+    pub discovered: Discovered,
+    pub items: Vec<PreviewItem>,
+    pub webhooks: Vec<PreviewWebhook>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreviewItem {
+    pub guid: String,
+    pub link: String,
+    pub title: Option<String>,
+    pub published_at: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreviewWebhook {
+    pub url: String,
+    pub kind: &'static str,
+    pub digest: bool,
+    pub payloads: Vec<String>,
+}
+
+impl fmt::Display for PreviewReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "feed {} {} ({} items)",
+            self.kind,
+            self.url,
+            self.items.len()
+        )?;
+        write!(f, "{}", self.discovered)?;
+        for item in &self.items {
+            writeln!(f)?;
+            writeln!(f, "guid:       {}", item.guid)?;
+            writeln!(f, "link:       {}", item.link)?;
+            writeln!(f, "title:      {}", item.title.as_deref().unwrap_or("-"))?;
+            writeln!(
+                f,
+                "published:  {}",
+                item.published_at.as_deref().unwrap_or("-")
+            )?;
+            if let Some(updated_at) = &item.updated_at {
+                writeln!(f, "updated:    {}", updated_at)?;
+            }
+        }
+        for webhook in &self.webhooks {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "webhook {} {}{} ({} payloads)",
+                webhook.kind,
+                webhook.url,
+                if webhook.digest { " as digest" } else { "" },
+                webhook.payloads.len()
+            )?;
+            for payload in &webhook.payloads {
+                writeln!(f, "{}", payload)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fetches a feed, either a configured one or one given by `kind` and
+/// `url`, and runs it through the poll loop against an empty database
+/// in memory: links are normalized, items saved and de-duplicated and
+/// the payloads rendered for the webhooks of the feed, or for
+/// `webhook_urls` if given. All items are treated as new, regardless of
+/// `backfill` and of what is saved in the real database.
+pub async fn preview(
+    config_path: &str,
+    feed: Option<&str>,
+    kind: Option<&str>,
+    url: Option<&str>,
+    webhook_urls: &[String],
+    output: OutputFormat,
+) -> Result<bool> {
+    let mut config = read_config_file(config_path.to_string())
+        .await
+        .context("failed to read config")?;
+
+    let mut feed_config = match (feed, kind, url) {
+        (Some(feed), _, _) => config
+            .feeds
+            .iter()
+            .find(|f| f.url == feed)
+            .cloned()
+            .ok_or_else(|| anyhow!("no configured feed with url '{}'", feed))?,
+        (None, Some(kind), Some(url)) => FeedConfig {
+            kind: kind.to_string(),
+            url: url.to_string(),
+            ..Default::default()
+        },
+        _ => return Err(anyhow!("either a feed or its kind and url are required")),
+    };
+    if !webhook_urls.is_empty() {
+        feed_config.webhook_urls = Some(webhook_urls.iter().cloned().map(Secret::new).collect());
+    }
+    config.feeds = vec![feed_config];
+
+    let db = get_memory_db_pool().await?;
+    let scheduler = Scheduler::load(config, &db)
+        .await
+        .context("invalid config")?;
+    let feed = scheduler.feeds[0].lock().await;
+
+    let mut items = match feed.fetch().await {
+        Ok(items) => items,
+        // The page links feeds, just none of the kind; list them
+        Err(e) if !feed.discovered().candidates.is_empty() => {
+            print_report(
+                output,
+                &PreviewReport {
+                    kind: feed.kind(),
+                    url: feed.url(),
+                    discovered: feed.discovered(),
+                    items: vec![],
+                    webhooks: vec![],
+                },
+            )?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    scheduler.link_normalizer.normalize(&mut items).await;
+
+    let mut saved = vec![];
+    for item in items {
+        // Items with the same guid are saved only once
+        if item.save().await? != Saved::New {
+            continue;
+        }
+        let item_id = item.id().await?;
+        for url in feed.webhook_urls().unwrap_or_default() {
+            let webhook = webhook_from_url(url.clone(), &scheduler.client)
+                .with_context(|| format!("invalid webhook '{}'", url))?;
+            let notification = Notification {
+                feed_item_id: item_id,
+                webhook_id: webhook.save(&db).await?,
+                revision: 0,
+                created_at: None,
+                replayed: false,
+            };
+            notification.save(&db, false).await?;
+        }
+        saved.push(PreviewItem {
+            guid: item.guid,
+            link: item.link,
+            title: item.title,
+            published_at: item.published_at,
+            updated_at: item.updated_at,
+        });
+    }
+
+    // Delivery windows only defer notifications, so they are ignored
+    let notifications = Notifications::get_unsent(&db, &HashMap::new(), Utc::now()).await?;
+    let notifications = match &scheduler.dedup {
+        Some(dedup) => dedup.filter(&db, notifications).await?,
+        None => notifications,
+    };
+
+    let mut by_webhook: Vec<(i64, Vec<FeedItem>)> = vec![];
+    for notification in notifications {
+        let item = notification.feed_item(&db).await?;
+        match by_webhook
+            .iter_mut()
+            .find(|(id, _)| *id == notification.webhook_id)
+        {
+            Some((_, items)) => items.push(item),
+            None => by_webhook.push((notification.webhook_id, vec![item])),
+        }
+    }
+
+    let mut webhooks = vec![];
+    for (webhook_id, items) in by_webhook {
+        let webhook = Webhooks::get(&db, webhook_id, &scheduler.client).await?;
+        let digest = scheduler
+            .digest_schedules
+            .contains_key(webhook.url().expose());
+        let payloads = if digest {
+            webhook
+                .render_digest(&items)?
+                .into_iter()
+                .map(|message| message.payload)
+                .collect()
+        } else {
+            items
+                .iter()
+                .map(|item| webhook.render(item))
+                .collect::<Result<_>>()?
+        };
+        webhooks.push(PreviewWebhook {
+            url: webhook.url().to_string(),
+            kind: webhook.kind(),
+            digest,
+            payloads,
+        });
+    }
+
+    print_report(
+        output,
+        &PreviewReport {
+            kind: feed.kind(),
+            url: feed.url(),
+            discovered: feed.discovered(),
+            items: saved,
+            webhooks,
+        },
+    )?;
+
+    Ok(true)
+}
@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::info;
+
+/// How often the config file is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `Reload` tells whether the config should be reloaded, either because
+/// of SIGHUP or because the config file changed
+#[derive(Debug)]
+pub struct Reload {
+    rx: watch::Receiver<()>,
+    pending: bool,
+}
+
+impl Reload {
+    /// Starts watching for SIGHUP and changes of the file at `path` in
+    /// the background
+    pub fn listen(path: &str) -> Result<Self> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let (tx, rx) = watch::channel(());
+
+        // The modification time is polled rather than watched with
+        // inotify & co, as editors and Kubernetes replace the file
+        // instead of changing it
+        let path = PathBuf::from(path);
+        let mut last_modified = modified(&path);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let m = modified(&path);
+                        if m == last_modified {
+                            continue;
+                        }
+                        last_modified = m;
+                        info!(path = %path.display(), "config file changed");
+                    }
+                    _ = sighup.recv() => info!("received SIGHUP"),
+                }
+                if tx.send(()).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self { rx, pending: false })
+    }
+
+    /// Returns true once for every requested reload
+    pub fn take_requested(&mut self) -> bool {
+        let requested = self.pending || self.rx.has_changed().unwrap_or(false);
+        self.rx.borrow_and_update();
+        self.pending = false;
+
+        requested
+    }
+
+    /// Waits until a reload is requested
+    pub async fn requested(&mut self) {
+        if self.rx.changed().await.is_ok() {
+            self.pending = true;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
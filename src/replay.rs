@@ -0,0 +1,123 @@
+use std::cmp::Reverse;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::prelude::*;
+
+/// `Replay` selects notifications to send again: those delivered to
+/// `webhook`, about items of `feeds` and sent between `sent_after` and
+/// `sent_before`. With `last`, the `last` newest items of every feed in
+/// `feeds` are sent to `webhook` instead, whether they were sent to it
+/// before or not, e.g. for a newly added webhook.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replay {
+    /// Url or id of the webhook
+    pub webhook: Option<String>,
+    #[serde(default)]
+    pub feeds: Vec<i64>,
+    pub sent_after: Option<NaiveDateTime>,
+    pub sent_before: Option<NaiveDateTime>,
+    pub last: Option<usize>,
+}
+
+impl Replay {
+    /// Queues the selected notifications to be sent by the poll loop and
+    /// returns how many were queued
+    pub async fn run(&self, db: &Pool<Sqlite>, client: &HttpClient) -> Result<u64> {
+        let webhook_id = match &self.webhook {
+            Some(webhook) => Some(webhook_id(db, webhook, client).await?),
+            None => None,
+        };
+
+        let n = match self.last {
+            Some(n) => n,
+            None => return self.requeue(db, webhook_id).await,
+        };
+        let webhook_id =
+            webhook_id.ok_or_else(|| anyhow!("sending the last items of feeds needs a webhook"))?;
+        if self.feeds.is_empty() {
+            return Err(anyhow!(
+                "sending the last items of feeds needs at least one feed"
+            ));
+        }
+        if self.sent_after.is_some() || self.sent_before.is_some() {
+            return Err(anyhow!(
+                "the last items of feeds can't be selected by the time they were sent"
+            ));
+        }
+
+        let mut queued = 0;
+        for feed_id in &self.feeds {
+            // Items are listed newest first; items without publication
+            // date are sorted last
+            let mut items = FeedItems::list(db, *feed_id, -1).await?;
+            items.sort_by_key(|item| Reverse(item.published()));
+
+            for item in items.iter().take(n) {
+                let notification = Notification {
+                    feed_item_id: item.id,
+                    webhook_id,
+                    revision: 0,
+                    created_at: None,
+                    replayed: true,
+                };
+                notification.requeue(db).await?;
+                queued += 1;
+            }
+        }
+
+        Ok(queued)
+    }
+
+    async fn requeue(&self, db: &Pool<Sqlite>, webhook_id: Option<i64>) -> Result<u64> {
+        if webhook_id.is_none()
+            && self.feeds.is_empty()
+            && self.sent_after.is_none()
+            && self.sent_before.is_none()
+        {
+            return Err(anyhow!(
+                "refusing to send all notifications again, select them by webhook, feed or time"
+            ));
+        }
+
+        let feed_ids = match self.feeds.is_empty() {
+            true => vec![None],
+            false => self.feeds.iter().copied().map(Some).collect(),
+        };
+        let mut queued = 0;
+        for feed_id in feed_ids {
+            let filter = NotificationFilter {
+                webhook_id,
+                feed_id,
+                after: self.sent_after,
+                before: self.sent_before,
+                ..Default::default()
+            };
+            queued += Notifications::requeue(db, &filter).await?;
+        }
+
+        Ok(queued)
+    }
+}
+
+/// Returns the id of the webhook with the id or url `webhook`; webhooks
+/// given by url are saved if they aren't yet
+async fn webhook_id(db: &Pool<Sqlite>, webhook: &str, client: &HttpClient) -> Result<i64> {
+    match webhook.parse::<i64>() {
+        Ok(id) => {
+            Webhooks::get(db, id, client)
+                .await
+                .with_context(|| format!("no webhook with id {}", id))?;
+            Ok(id)
+        }
+        Err(_) => {
+            webhook_from_url(Secret::new(webhook.to_string()), client)?
+                .save(db)
+                .await
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    pub fn from_config(config: &DigestConfig) -> Result<Self> {
+        match (config.interval, &config.cron) {
+            (Some(secs), None) => Ok(Schedule::Interval(Duration::seconds(secs.try_into()?))),
+            (None, Some(expr)) => match cron::Schedule::from_str(expr) {
+                Ok(s) => Ok(Schedule::Cron(Box::new(s))),
+                Err(e) => Err(anyhow!("invalid cron expression '{}': {}", expr, e)),
+            },
+            _ => Err(anyhow!(
+                "digest needs exactly one of `interval` or `cron` to be set"
+            )),
+        }
+    }
+
+    /// Returns the first time the schedule fires after `since`
+    pub fn next_after(&self, since: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(d) => Some(since + *d),
+            Schedule::Cron(s) => s.after(&since).next(),
+        }
+    }
+
+    /// Returns true if the schedule fired between `since` and `now`
+    pub fn is_due(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        match self.next_after(since) {
+            Some(t) => t <= now,
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeliveryWindow {
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    timezone: Tz,
+    digest: bool,
+}
+
+impl DeliveryWindow {
+    pub fn from_config(config: &DeliveryWindowConfig) -> Result<Self> {
+        let days = match &config.days {
+            Some(days) => days
+                .iter()
+                .map(|d| {
+                    Weekday::from_str(d).map_err(|_| anyhow!("invalid day of the week '{}'", d))
+                })
+                .collect::<Result<_>>()?,
+            None => vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+            ],
+        };
+        let start = NaiveTime::parse_from_str(&config.start, "%H:%M")
+            .map_err(|e| anyhow!("invalid start time '{}': {}", config.start, e))?;
+        let end = NaiveTime::parse_from_str(&config.end, "%H:%M")
+            .map_err(|e| anyhow!("invalid end time '{}': {}", config.end, e))?;
+        if start == end {
+            return Err(anyhow!(
+                "delivery window starts and ends at '{}', so it's never open",
+                config.start
+            ));
+        }
+        if days.is_empty() {
+            return Err(anyhow!("delivery window has no days, so it's never open"));
+        }
+        let timezone = match &config.timezone {
+            Some(tz) => {
+                Tz::from_str(tz).map_err(|e| anyhow!("invalid time zone '{}': {}", tz, e))?
+            }
+            None => Tz::UTC,
+        };
+
+        Ok(Self {
+            days,
+            start,
+            end,
+            timezone,
+            digest: config.digest.unwrap_or(false),
+        })
+    }
+
+    /// Returns true if `t` falls into the window; windows that end
+    /// before they start span midnight and belong to the day they
+    /// start on
+    pub fn contains(&self, t: DateTime<Utc>) -> bool {
+        let local = t.with_timezone(&self.timezone);
+        let time = local.time();
+        let day = local.weekday();
+
+        if self.start <= self.end {
+            self.days.contains(&day) && self.start <= time && time < self.end
+        } else if time >= self.start {
+            self.days.contains(&day)
+        } else {
+            self.days.contains(&day.pred()) && time < self.end
+        }
+    }
+}
+
+/// `DeliveryWindows` are the delivery windows of a webhook;
+/// notifications are deferred while none of them is open.
+#[derive(Clone, Debug)]
+pub struct DeliveryWindows {
+    windows: Vec<DeliveryWindow>,
+}
+
+impl DeliveryWindows {
+    pub fn from_config(configs: &[DeliveryWindowConfig]) -> Result<Self> {
+        if configs.is_empty() {
+            return Err(anyhow!(
+                "`delivery_windows` is empty, so notifications would never be delivered"
+            ));
+        }
+
+        Ok(Self {
+            windows: configs
+                .iter()
+                .map(DeliveryWindow::from_config)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn is_open(&self, t: DateTime<Utc>) -> bool {
+        self.windows.iter().any(|w| w.contains(t))
+    }
+
+    /// Returns true if a notification created at `created_at` was
+    /// deferred and is to be delivered as part of a digest now that
+    /// a window with `digest = true` is open
+    pub fn is_deferred_digest(
+        &self,
+        created_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let digest = self.windows.iter().any(|w| w.digest && w.contains(now));
+
+        match created_at {
+            Some(created_at) => digest && !self.is_open(created_at),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(days: &[&str], start: &str, end: &str, timezone: &str) -> DeliveryWindow {
+        DeliveryWindow::from_config(&DeliveryWindowConfig {
+            days: Some(days.iter().map(|d| d.to_string()).collect()),
+            start: start.to_string(),
+            end: end.to_string(),
+            timezone: Some(timezone.to_string()),
+            digest: None,
+        })
+        .unwrap()
+    }
+
+    fn utc(t: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(t).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn contains_in_time_zone() {
+        let w = window(&["Mon"], "09:00", "17:00", "America/New_York");
+
+        // EST
+        assert!(!w.contains(utc("2026-01-05T13:59:00Z")));
+        assert!(w.contains(utc("2026-01-05T14:00:00Z")));
+        assert!(w.contains(utc("2026-01-05T21:59:00Z")));
+        assert!(!w.contains(utc("2026-01-05T22:00:00Z")));
+        // EDT
+        assert!(w.contains(utc("2026-07-06T13:00:00Z")));
+        assert!(!w.contains(utc("2026-07-06T21:00:00Z")));
+        // Tuesday
+        assert!(!w.contains(utc("2026-01-06T15:00:00Z")));
+    }
+
+    #[test]
+    fn contains_across_midnight() {
+        let w = window(&["Sat"], "22:00", "02:00", "UTC");
+
+        assert!(!w.contains(utc("2026-01-03T21:59:00Z")));
+        assert!(w.contains(utc("2026-01-03T22:00:00Z")));
+        // Sunday morning belongs to the window of Saturday
+        assert!(w.contains(utc("2026-01-04T01:59:00Z")));
+        assert!(!w.contains(utc("2026-01-04T02:00:00Z")));
+        // Sunday night isn't in the window, nor is Saturday morning
+        assert!(!w.contains(utc("2026-01-04T23:00:00Z")));
+        assert!(!w.contains(utc("2026-01-03T01:00:00Z")));
+    }
+
+    #[test]
+    fn contains_across_midnight_on_dst_change() {
+        // Clocks in Berlin go from 02:00 CET to 03:00 CEST on Sunday,
+        // 2026-03-29
+        let w = window(&["Sat"], "22:00", "02:00", "Europe/Berlin");
+
+        assert!(w.contains(utc("2026-03-28T21:00:00Z")));
+        assert!(w.contains(utc("2026-03-29T00:59:00Z")));
+        assert!(!w.contains(utc("2026-03-29T01:00:00Z")));
+    }
+
+    #[test]
+    fn windows_that_are_never_open_are_rejected() {
+        let config = DeliveryWindowConfig {
+            days: None,
+            start: "09:00".to_string(),
+            end: "09:00".to_string(),
+            timezone: None,
+            digest: None,
+        };
+        assert!(DeliveryWindow::from_config(&config).is_err());
+
+        let config = DeliveryWindowConfig {
+            days: Some(vec![]),
+            end: "17:00".to_string(),
+            ..config
+        };
+        assert!(DeliveryWindow::from_config(&config).is_err());
+
+        assert!(DeliveryWindows::from_config(&[]).is_err());
+    }
+}
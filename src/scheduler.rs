@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use sqlx::{Pool, Sqlite};
+use tokio::sync::Mutex;
+
+use crate::prelude::*;
+
+/// `Scheduler` holds everything the poll loop derives from the config.
+/// It's replaced as a whole when the config is reloaded; pending
+/// notifications are kept in the database and aren't affected, feeds
+/// whose kind and url are unchanged keep their state.
+pub struct Scheduler {
+    pub config: Config,
+    pub poll: bool,
+    pub poll_sleep_dur: Duration,
+    /// In-flight notifications are finished on shutdown, but not
+    /// longer than this
+    pub shutdown_timeout: Duration,
+    /// Digest schedules by webhook url
+    pub digest_schedules: HashMap<String, Schedule>,
+    /// Delivery windows by webhook url
+    pub delivery_windows: HashMap<String, DeliveryWindows>,
+    /// Urls of the feeds that want notifications for updated items
+    pub notify_updates: Arc<HashSet<String>>,
+    pub dedup: Option<Dedup>,
+    pub client: HttpClient,
+    pub link_normalizer: LinkNormalizer,
+    pub feeds: Vec<Arc<Mutex<Box<dyn Feed>>>>,
+    /// State of the feeds by kind and url
+    pub feed_states: HashMap<(String, String), FeedState>,
+}
+
+impl Scheduler {
+    /// Validates `config` and returns the scheduler for it; new feeds
+    /// are saved to the database
+    pub async fn load(config: Config, db: &Pool<Sqlite>) -> Result<Self> {
+        Self::load_with_states(config, db, &HashMap::new()).await
+    }
+
+    /// Returns the scheduler for `config` to replace this one with; feeds
+    /// keep their state, e.g. the feed found on their HTML page or a
+    /// parse that timed out but still runs
+    pub async fn reload(&self, config: Config, db: &Pool<Sqlite>) -> Result<Self> {
+        Self::load_with_states(config, db, &self.feed_states).await
+    }
+
+    async fn load_with_states(
+        config: Config,
+        db: &Pool<Sqlite>,
+        states: &HashMap<(String, String), FeedState>,
+    ) -> Result<Self> {
+        let poll = config.poll.unwrap_or(true);
+        let poll_sleep_dur = Duration::from_secs(config.poll_sleep_dur.unwrap_or(600));
+        let shutdown_timeout = Duration::from_secs(config.shutdown_timeout.unwrap_or(30));
+
+        let mut digest_schedules: HashMap<String, Schedule> = HashMap::new();
+        let mut delivery_windows: HashMap<String, DeliveryWindows> = HashMap::new();
+        let mut names = HashSet::new();
+        for webhook in config.webhooks.iter().flatten() {
+            if let Some(name) = &webhook.name {
+                if !names.insert(name) {
+                    return Err(anyhow!("more than one webhook is named '{}'", name));
+                }
+            }
+            let url = webhook.url.expose().to_string();
+            if let Some(windows) = &webhook.delivery_windows {
+                let windows = DeliveryWindows::from_config(windows).with_context(|| {
+                    format!("invalid delivery window for webhook '{}'", webhook.url)
+                })?;
+                delivery_windows.insert(url.clone(), windows);
+            }
+            if let Some(digest) = &webhook.digest {
+                let schedule = Schedule::from_config(digest)
+                    .with_context(|| format!("invalid digest for webhook '{}'", webhook.url))?;
+                digest_schedules.insert(url, schedule);
+            }
+        }
+
+        let notify_updates = Arc::new(
+            config
+                .feeds
+                .iter()
+                .filter(|f| f.notify_updates.unwrap_or(false))
+                .map(|f| f.url.clone())
+                .collect(),
+        );
+
+        let dedup = config
+            .dedup
+            .as_ref()
+            .map(Dedup::from_config)
+            .transpose()
+            .context("invalid dedup config")?;
+
+        let network_policy = NetworkPolicy::from_config(config.network_policy.as_ref())
+            .context("invalid network policy")?;
+        config.check_network_policy()?;
+
+        let http_config = config.http.clone().unwrap_or_default();
+        let client =
+            HttpClient::new(&http_config, &network_policy).context("invalid http config")?;
+
+        let link_normalizer = LinkNormalizer::new(config.links.as_ref(), &client, db);
+
+        let mut feeds: Vec<Arc<Mutex<Box<dyn Feed>>>> = vec![];
+        let mut feed_states = HashMap::new();
+        for f in &config.feeds {
+            let webhook_urls = config.feed_webhook_urls(f);
+
+            // Feeds with their own http settings or credentials
+            // get their own client
+            let client = if f.http.is_some() || f.auth.is_some() || f.headers.is_some() {
+                let feed_http_config = http_config.merge(&f.http.clone().unwrap_or_default());
+                let headers = feed_headers(f)?;
+                HttpClient::with_headers(&feed_http_config, &network_policy, headers)
+            } else {
+                Ok(client.clone())
+            };
+
+            let limits_config = match (&config.limits, &f.limits) {
+                (Some(global), Some(feed)) => global.merge(feed),
+                (global, feed) => feed.clone().or(global.clone()).unwrap_or_default(),
+            };
+            let limits = Limits::new(&limits_config);
+
+            let key = (f.kind.clone(), f.url.clone());
+            let state = states.get(&key).cloned().unwrap_or_default();
+            let feed = client
+                .and_then(|c| {
+                    feed_from_config(&f.kind, &f.url, webhook_urls, c, limits, db, state.clone())
+                })
+                .with_context(|| format!("invalid feed '{}'", redact_url(&f.url)))?;
+            feeds.push(feed);
+            feed_states.insert(key, state);
+        }
+
+        for feed in &feeds {
+            feed.lock()
+                .await
+                .save()
+                .await
+                .context("failed to save feed")?;
+        }
+
+        Ok(Self {
+            config,
+            poll,
+            poll_sleep_dur,
+            shutdown_timeout,
+            digest_schedules,
+            delivery_windows,
+            notify_updates,
+            dedup,
+            client,
+            link_normalizer,
+            feeds,
+            feed_states,
+        })
+    }
+}
+
+fn feed_from_config(
+    kind: &str,
+    url: &str,
+    webhook_urls: Option<Vec<Secret>>,
+    client: HttpClient,
+    limits: Limits,
+    db: &Pool<Sqlite>,
+    state: FeedState,
+) -> Result<Arc<Mutex<Box<dyn Feed>>>> {
+    match kind {
+        "atom" => Ok(Arc::new(Mutex::new(Box::new(AtomFeed::new(
+            url.to_string(),
+            webhook_urls,
+            client,
+            limits,
+            db,
+            state,
+        )?)))),
+        "rss" => Ok(Arc::new(Mutex::new(Box::new(RSSFeed::new(
+            url.to_string(),
+            webhook_urls,
+            client,
+            limits,
+            db,
+            state,
+        )?)))),
+        _ => Err(anyhow!(
+            "unknown feed kind '{}' for feed '{}'",
+            kind,
+            redact_url(url)
+        )),
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::Result;
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, warn};
+
+use crate::prelude::*;
+
+/// `Api` holds what the endpoints that change the state need; requests
+/// to them must carry `token` as bearer token
+pub struct Api {
+    pub db: Pool<Sqlite>,
+    pub client: HttpClient,
+    pub token: Secret,
+}
+
+impl Api {
+    fn is_authorized(&self, req: &HttpRequest) -> bool {
+        let token = req
+            .headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+
+        match token {
+            Some(token) => constant_time_eq(token.as_bytes(), self.token.expose().as_bytes()),
+            None => false,
+        }
+    }
+}
+
+/// Compares `a` and `b` in a time that doesn't depend on where they
+/// differ, so that the token can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn metrics(metrics: web::Data<Arc<Metrics>>) -> HttpResponse {
+    match metrics.encode() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+            .body(body),
+        Err(e) => {
+            error!(error = %e, "failed to encode metrics");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn healthz(health: web::Data<Arc<Health>>) -> HttpResponse {
+    match health.check(Utc::now()) {
+        Ok(_) => HttpResponse::Ok().body("ok\n"),
+        Err(e) => {
+            warn!(error = error_chain(&e), "unhealthy");
+            HttpResponse::ServiceUnavailable().body(format!("{}\n", e))
+        }
+    }
+}
+
+async fn readyz(health: web::Data<Arc<Health>>) -> HttpResponse {
+    match health.check_ready().await {
+        Ok(_) => HttpResponse::Ok().body("ok\n"),
+        Err(e) => {
+            warn!(error = error_chain(&e), "not ready");
+            HttpResponse::ServiceUnavailable().body(format!("{:#}\n", e))
+        }
+    }
+}
+
+async fn replay(
+    api: web::Data<Arc<Api>>,
+    req: HttpRequest,
+    body: web::Json<Replay>,
+) -> HttpResponse {
+    if !api.is_authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match body.run(&api.db, &api.client).await {
+        Ok(queued) => {
+            info!(queued, "queued notifications to be sent again");
+            HttpResponse::Ok().json(serde_json::json!({ "queued": queued }))
+        }
+        Err(e) => {
+            warn!(error = error_chain(&e), "failed to replay notifications");
+            HttpResponse::BadRequest().body(format!("{:#}\n", e))
+        }
+    }
+}
+
+/// Starts the HTTP listener for `/metrics`, `/healthz` and `/readyz` on
+/// `listen` in the background; with `api`, `/notifications/replay` is
+/// served too
+pub fn serve(
+    listen: &str,
+    m: Arc<Metrics>,
+    health: Arc<Health>,
+    api: Option<Arc<Api>>,
+) -> Result<()> {
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
+            .app_data(web::Data::new(m.clone()))
+            .app_data(web::Data::new(health.clone()))
+            .route("/metrics", web::get().to(metrics))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz));
+        if let Some(api) = &api {
+            app = app
+                .app_data(web::Data::new(api.clone()))
+                .route("/notifications/replay", web::post().to(replay));
+        }
+        app
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(listen)?
+    .run();
+
+    info!(listen, "listening");
+    tokio::spawn(server);
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// `Shutdown` tells whether the process was asked to stop with SIGTERM
+/// or SIGINT; a second signal exits immediately
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Starts listening for signals in the background
+    pub fn listen() -> Result<Self> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let (tx, rx) = watch::channel(false);
+
+        // The handlers stay installed, so a second signal stops the
+        // process right away instead of waiting for notifications
+        tokio::spawn(async move {
+            loop {
+                let signal = tokio::select! {
+                    _ = sigterm.recv() => "SIGTERM",
+                    _ = sigint.recv() => "SIGINT",
+                };
+                if *tx.borrow() {
+                    warn!(signal, "received second signal, exiting immediately");
+                    std::process::exit(1);
+                }
+                info!(signal, "shutting down");
+                let _ = tx.send(true);
+            }
+        });
+
+        Ok(Self { rx })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Waits until shutdown is requested
+    pub async fn requested(&mut self) {
+        // The sender is never dropped
+        let _ = self.rx.wait_for(|requested| *requested).await;
+    }
+}
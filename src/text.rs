@@ -0,0 +1,282 @@
+/// Maximum length of summaries in characters
+const SUMMARY_MAX_LEN: usize = 300;
+
+/// Elements whose content isn't text
+const SKIPPED_ELEMENTS: &[&str] = &["script", "style", "head", "template"];
+
+/// Elements that start a new line
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address",
+    "article",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// Returns `html` as plain text: tags are stripped, block elements
+/// become line breaks, list items bullets and entities are decoded
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match tag_end(rest) {
+            Some(end) => end,
+            None => {
+                // Not a tag, e.g. "a < b"
+                text.push('<');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+
+        if !closing && SKIPPED_ELEMENTS.contains(&name.as_str()) && !tag.ends_with('/') {
+            // Skip everything up to and including the closing tag
+            let closing_tag = format!("</{}", name);
+            rest = match rest.to_ascii_lowercase().find(&closing_tag) {
+                Some(i) => match rest[i..].find('>') {
+                    Some(j) => &rest[i + j + 1..],
+                    None => "",
+                },
+                None => "",
+            };
+            continue;
+        }
+        if name == "li" {
+            if !closing {
+                text.push_str("\n• ");
+            }
+        } else if BLOCK_ELEMENTS.contains(&name.as_str()) {
+            text.push('\n');
+        }
+    }
+    text.push_str(rest);
+
+    let text = html_escape::decode_html_entities(&text);
+
+    // Collapse whitespace within lines and allow at most one empty line
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() && lines.last().is_none_or(|l| l.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+
+    lines.join("\n")
+}
+
+/// Returns `html` as plain text on a single line, e.g. for titles
+pub fn html_to_line(html: &str) -> String {
+    html_to_text(html)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns `html` as plain text shortened to `SUMMARY_MAX_LEN`
+/// characters, e.g. for the summary of an item; `None` if there is no
+/// text
+pub fn html_to_summary(html: &str) -> Option<String> {
+    let text = html_to_text(html);
+    if text.is_empty() {
+        return None;
+    }
+    if text.chars().count() <= SUMMARY_MAX_LEN {
+        return Some(text);
+    }
+
+    let shortened: String = text.chars().take(SUMMARY_MAX_LEN - 1).collect();
+    Some(format!("{}…", shortened.trim_end()))
+}
+
+/// Returns the index of the `>` closing the tag `s` starts with, if
+/// `s` starts with a tag, a comment or a declaration
+fn tag_end(s: &str) -> Option<usize> {
+    let next = s[1..].chars().next()?;
+    if !(next.is_ascii_alphabetic() || next == '/' || next == '!' || next == '?') {
+        return None;
+    }
+    if s.starts_with("<!--") {
+        return s.find("-->").map(|i| i + 2);
+    }
+
+    let mut quote: Option<char> = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Returns `text` with Discord markdown characters escaped; headings,
+/// quotes and lists are only markdown at the start of a line
+pub fn escape_discord(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut line_start = true;
+    for c in text.chars() {
+        let block = line_start && matches!(c, '#' | '>' | '-');
+        if block || matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        line_start = c == '\n' || (line_start && c == ' ');
+    }
+    escaped
+}
+
+/// Returns `text` with the characters escaped that Slack mrkdwn
+/// uses for links and mentions
+// https://api.slack.com/reference/surfaces/formatting#escaping
+pub fn escape_slack(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Returns `text` escaped for the HTML `formatted_body` of Matrix
+/// messages, in which it's only used as text, not in attributes
+pub fn escape_matrix(text: &str) -> String {
+    html_escape::encode_text(text).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            html_to_text("Tom &amp; Jerry &lt;3 &quot;caf&eacute;&quot; &#8212; &#x263A;"),
+            "Tom & Jerry <3 \"café\" — ☺"
+        );
+        // Escaped markup stays text
+        assert_eq!(
+            html_to_line("&lt;em&gt;not a tag&lt;/em&gt;"),
+            "<em>not a tag</em>"
+        );
+    }
+
+    #[test]
+    fn line_breaks() {
+        assert_eq!(
+            html_to_text("<p>First   paragraph</p>\n\n\n<p>Second<br>line<br/>third</p>"),
+            "First paragraph\n\nSecond\nline\nthird"
+        );
+        assert_eq!(
+            html_to_text("<ul><li>one</li><li>two</li></ul>"),
+            "• one\n• two"
+        );
+        assert_eq!(
+            html_to_line("<h1>Title</h1><p>with\nbreaks</p>"),
+            "Title with breaks"
+        );
+    }
+
+    #[test]
+    fn nested_and_unclosed_tags() {
+        assert_eq!(
+            html_to_line("<div><p>A <em>very <strong>nested</strong></em> <a href=\"/x?a=1&b=2\" title='a > b'>link</a></p>"),
+            "A very nested link"
+        );
+        assert_eq!(html_to_line("<p>unclosed <b>bold"), "unclosed bold");
+        assert_eq!(html_to_line("a < b and c <d"), "a < b and c <d");
+        assert_eq!(
+            html_to_line("before<!-- <p>comment</p> -->after"),
+            "beforeafter"
+        );
+    }
+
+    #[test]
+    fn scripts_and_styles_are_dropped() {
+        assert_eq!(
+            html_to_line(
+                "<style>p { color: red }</style>Text<SCRIPT type=\"x\">alert('<p>')</script> only"
+            ),
+            "Text only"
+        );
+        assert_eq!(html_to_line("Text<script>never closed"), "Text");
+    }
+
+    #[test]
+    fn summaries() {
+        assert_eq!(html_to_summary("<p> </p>"), None);
+        assert_eq!(html_to_summary("<p>Short</p>").as_deref(), Some("Short"));
+
+        let long = format!("<p>{}</p>", "word ".repeat(100));
+        let summary = html_to_summary(&long).unwrap();
+        assert_eq!(summary.chars().count(), 300);
+        assert!(summary.ends_with("word…"));
+    }
+
+    #[test]
+    fn discord_markdown_is_escaped() {
+        assert_eq!(
+            escape_discord("*bold* _it_ ~~s~~ `code` [x](y) a|b \\"),
+            "\\*bold\\* \\_it\\_ \\~\\~s\\~\\~ \\`code\\` \\[x\\](y) a\\|b \\\\"
+        );
+        assert_eq!(
+            escape_discord("# Heading\n> quote\n- item\nnot # a - heading"),
+            "\\# Heading\n\\> quote\n\\- item\nnot # a - heading"
+        );
+    }
+
+    #[test]
+    fn slack_mrkdwn_is_escaped() {
+        assert_eq!(
+            escape_slack("<!channel> & <https://x|y>"),
+            "&lt;!channel&gt; &amp; &lt;https://x|y&gt;"
+        );
+    }
+
+    #[test]
+    fn matrix_html_is_escaped() {
+        assert_eq!(
+            escape_matrix("<b>Tom</b> & \"Jerry's\""),
+            "&lt;b&gt;Tom&lt;/b&gt; &amp; \"Jerry's\""
+        );
+    }
+}
@@ -1,66 +1,115 @@
 use crate::prelude::*;
 
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use serde::Serialize;
 use sqlx::{Pool, Sqlite};
+use url::Url;
+
+// https://discord.com/developers/docs/resources/message#create-message
+const DISCORD_MAX_MESSAGE_LEN: usize = 2000;
+// https://api.slack.com/methods/chat.postMessage#truncating
+const SLACK_MAX_MESSAGE_LEN: usize = 40000;
+// Events are limited to 64 KiB, with both the plain and the HTML body
+// https://spec.matrix.org/v1.11/client-server-api/#size-limits
+const MATRIX_MAX_MESSAGE_LEN: usize = 8000;
+// Responses of webhooks are only shown, e.g. by `test-webhook`
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
 
 #[async_trait]
 pub trait Webhook: Send + Sync + 'static {
     async fn push(&self, item: FeedItem) -> Result<()>;
     async fn save(&self, db: &Pool<Sqlite>) -> Result<i64>;
-    fn url(&self) -> String;
+    /// Sends a rendered payload and returns the response
+    async fn deliver(&self, payload: String) -> Result<Delivery>;
+    /// Returns the payload `push` sends for `item`
+    fn render(&self, item: &FeedItem) -> Result<String>;
+    /// Returns the messages of a digest of `items`, split if too long
+    /// for the target
+    fn render_digest(&self, items: &[FeedItem]) -> Result<Vec<DigestMessage>>;
+    fn url(&self) -> Secret;
+    /// Returns the kind of target, e.g. for metrics
+    fn kind(&self) -> &'static str;
+}
+
+/// `Delivery` is the response of a webhook to a payload; the stdout
+/// webhook has no status
+#[derive(Debug, Serialize)]
+pub struct Delivery {
+    pub status: Option<u16>,
+    pub body: String,
+}
+
+/// `DigestMessage` is a message of a digest; it holds the `items` items
+/// that follow the ones of the previous message
+#[derive(Debug)]
+pub struct DigestMessage {
+    pub payload: String,
+    pub items: usize,
+}
+
+impl Delivery {
+    /// Returns an error if the webhook didn't accept the payload, e.g.
+    /// because it was deleted or is rate limited
+    pub fn error_for_status(self) -> Result<Self> {
+        match self.status {
+            Some(status) if !(200..300).contains(&status) => {
+                let body: String = self.body.chars().take(200).collect();
+                Err(anyhow!(
+                    "webhook responded with status {}: {}",
+                    status,
+                    body
+                ))
+            }
+            _ => Ok(self),
+        }
+    }
 }
 
 pub struct WebhookDiscord {
-    pub url: String,
+    pub url: Secret,
+    pub client: HttpClient,
 }
 
 #[derive(Serialize)]
 struct DiscordMessage {
     content: String,
+    allowed_mentions: DiscordAllowedMentions,
 }
 
-impl WebhookDiscord {
-    fn render_message(&self, item: &FeedItem) -> Result<String> {
-        let msg_title = match &item.title {
-            Some(t) => format!("{}\n\n", t),
-            None => "".to_string(),
-        };
-        let msg_content = format!("{}{}", msg_title, item.link);
-        let msg = DiscordMessage {
-            content: msg_content,
-        };
-        Ok(serde_json::to_string(&msg)?)
-    }
+// Titles may contain things like "@everyone" which shouldn't ping anyone
+// https://discord.com/developers/docs/resources/message#allowed-mentions-object
+#[derive(Default, Serialize)]
+struct DiscordAllowedMentions {
+    parse: Vec<String>,
 }
 
 #[async_trait]
 impl Webhook for WebhookDiscord {
     async fn push(&self, item: FeedItem) -> Result<()> {
-        let message = self.render_message(&item)?;
-
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(15))
-            .build()?;
-        client
-            .post(&self.url)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .body(message)
-            .send()
-            .await?;
+        let message = self.render(&item)?;
+
+        self.deliver(message).await?.error_for_status()?;
 
         Ok(())
     }
 
+    async fn deliver(&self, payload: String) -> Result<Delivery> {
+        post_json(&self.client, &self.url, payload).await
+    }
+
     async fn save(&self, db: &Pool<Sqlite>) -> Result<i64> {
+        let url = self.url.expose();
+
         sqlx::query!(
             r#"
             INSERT INTO webhooks (url)
             VALUES (?)
             ON CONFLICT (url) DO NOTHING
             "#,
-            self.url,
+            url,
         )
         .execute(db)
         .await?;
@@ -69,7 +118,7 @@ impl Webhook for WebhookDiscord {
             r#"
             SELECT id FROM webhooks WHERE url = ?
             "#,
-            self.url,
+            url,
         )
         .fetch_one(db)
         .await?;
@@ -77,16 +126,44 @@ impl Webhook for WebhookDiscord {
         Ok(result.id)
     }
 
-    fn url(&self) -> String {
+    fn render(&self, item: &FeedItem) -> Result<String> {
+        let msg = DiscordMessage {
+            content: message_text(item, escape_discord),
+            allowed_mentions: DiscordAllowedMentions::default(),
+        };
+        Ok(serde_json::to_string(&msg)?)
+    }
+
+    fn render_digest(&self, items: &[FeedItem]) -> Result<Vec<DigestMessage>> {
+        split_digest(items, DISCORD_MAX_MESSAGE_LEN, escape_discord)
+            .into_iter()
+            .map(|(content, items)| {
+                Ok(DigestMessage {
+                    payload: serde_json::to_string(&DiscordMessage {
+                        content,
+                        allowed_mentions: DiscordAllowedMentions::default(),
+                    })?,
+                    items,
+                })
+            })
+            .collect()
+    }
+
+    fn url(&self) -> Secret {
         self.url.clone()
     }
+
+    fn kind(&self) -> &'static str {
+        "discord"
+    }
 }
 
 // TODO: currently untested, as I'm not using Slack;
 // if you do, please test this and let me know if it
 // works as expected!
 pub struct WebhookSlack {
-    pub url: String,
+    pub url: Secret,
+    pub client: HttpClient,
 }
 
 #[derive(Serialize)]
@@ -94,44 +171,133 @@ struct SlackMessage {
     text: String,
 }
 
-impl WebhookSlack {
-    fn render_message(&self, item: &FeedItem) -> Result<String> {
-        let msg_title = match &item.title {
-            Some(t) => format!("{}\n\n", t),
-            None => "".to_string(),
+#[async_trait]
+impl Webhook for WebhookSlack {
+    async fn push(&self, item: FeedItem) -> Result<()> {
+        let message = self.render(&item)?;
+
+        self.deliver(message).await?.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn deliver(&self, payload: String) -> Result<Delivery> {
+        post_json(&self.client, &self.url, payload).await
+    }
+
+    async fn save(&self, db: &Pool<Sqlite>) -> Result<i64> {
+        let url = self.url.expose();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO webhooks (url)
+            VALUES (?)
+            ON CONFLICT (url) DO NOTHING
+            "#,
+            url,
+        )
+        .execute(db)
+        .await?;
+
+        let result = sqlx::query!(
+            r#"
+            SELECT id FROM webhooks WHERE url = ?
+            "#,
+            url,
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(result.id)
+    }
+
+    fn render(&self, item: &FeedItem) -> Result<String> {
+        let msg = SlackMessage {
+            text: message_text(item, escape_slack),
         };
-        let msg_content = format!("{}{}", msg_title, item.link);
-        let msg = SlackMessage { text: msg_content };
         Ok(serde_json::to_string(&msg)?)
     }
+
+    fn render_digest(&self, items: &[FeedItem]) -> Result<Vec<DigestMessage>> {
+        split_digest(items, SLACK_MAX_MESSAGE_LEN, escape_slack)
+            .into_iter()
+            .map(|(text, items)| {
+                Ok(DigestMessage {
+                    payload: serde_json::to_string(&SlackMessage { text })?,
+                    items,
+                })
+            })
+            .collect()
+    }
+
+    fn url(&self) -> Secret {
+        self.url.clone()
+    }
+
+    fn kind(&self) -> &'static str {
+        "slack"
+    }
+}
+
+/// `WebhookMatrix` sends messages to a Matrix room through the client
+/// API of a homeserver. Its url is the url to send messages to the room
+/// with, including the access token of the sending user, e.g.
+/// "https://matrix.org/_matrix/client/v3/rooms/!id:matrix.org/send/m.room.message?access_token=...".
+pub struct WebhookMatrix {
+    pub url: Secret,
+    pub client: HttpClient,
+}
+
+// https://spec.matrix.org/v1.11/client-server-api/#mtext
+#[derive(Serialize)]
+struct MatrixMessage {
+    msgtype: &'static str,
+    body: String,
+    format: &'static str,
+    formatted_body: String,
+}
+
+impl MatrixMessage {
+    fn new(body: String) -> Self {
+        Self {
+            msgtype: "m.text",
+            formatted_body: escape_matrix(&body).replace('\n', "<br>"),
+            body,
+            format: "org.matrix.custom.html",
+        }
+    }
 }
 
 #[async_trait]
-impl Webhook for WebhookSlack {
+impl Webhook for WebhookMatrix {
     async fn push(&self, item: FeedItem) -> Result<()> {
-        let message = self.render_message(&item)?;
-
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(15))
-            .build()?;
-        client
-            .post(&self.url)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .body(message)
-            .send()
-            .await?;
+        let message = self.render(&item)?;
+
+        self.deliver(message).await?.error_for_status()?;
 
         Ok(())
     }
 
+    async fn deliver(&self, payload: String) -> Result<Delivery> {
+        let (url, token) = matrix_send_url(self.url.expose())?;
+        let mut request = self.client.put(url.as_str())?;
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+
+        send_json(request, payload).await
+    }
+
     async fn save(&self, db: &Pool<Sqlite>) -> Result<i64> {
+        let url = self.url.expose();
+
         sqlx::query!(
             r#"
             INSERT INTO webhooks (url)
             VALUES (?)
             ON CONFLICT (url) DO NOTHING
             "#,
-            self.url,
+            url,
         )
         .execute(db)
         .await?;
@@ -140,7 +306,7 @@ impl Webhook for WebhookSlack {
             r#"
             SELECT id FROM webhooks WHERE url = ?
             "#,
-            self.url,
+            url,
         )
         .fetch_one(db)
         .await?;
@@ -148,31 +314,105 @@ impl Webhook for WebhookSlack {
         Ok(result.id)
     }
 
-    fn url(&self) -> String {
+    fn render(&self, item: &FeedItem) -> Result<String> {
+        let msg = MatrixMessage::new(message_text(item, |t| t.to_string()));
+        Ok(serde_json::to_string(&msg)?)
+    }
+
+    fn render_digest(&self, items: &[FeedItem]) -> Result<Vec<DigestMessage>> {
+        split_digest(items, MATRIX_MAX_MESSAGE_LEN, |t| t.to_string())
+            .into_iter()
+            .map(|(body, items)| {
+                Ok(DigestMessage {
+                    payload: serde_json::to_string(&MatrixMessage::new(body))?,
+                    items,
+                })
+            })
+            .collect()
+    }
+
+    fn url(&self) -> Secret {
         self.url.clone()
     }
+
+    fn kind(&self) -> &'static str {
+        "matrix"
+    }
+}
+
+/// Returns the url to send a message to with the Matrix webhook url
+/// `url` and the access token of the url. The token is sent in a
+/// header, as tokens in urls are deprecated; messages are sent with a
+/// new transaction id, which homeservers use to drop retried messages.
+fn matrix_send_url(url: &str) -> Result<(Url, Option<String>)> {
+    let mut url = Url::parse(url)?;
+
+    let mut token = None;
+    let mut query = vec![];
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "access_token" => token = Some(v.into_owned()),
+            _ => query.push((k.into_owned(), v.into_owned())),
+        }
+    }
+    match query.is_empty() {
+        true => url.set_query(None),
+        false => {
+            url.query_pairs_mut().clear().extend_pairs(query);
+        }
+    }
+
+    static TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let transaction_id = format!(
+        "feedcruncher.{}.{}",
+        nanos,
+        TRANSACTIONS.fetch_add(1, Ordering::Relaxed)
+    );
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid Matrix webhook url"))?
+        .pop_if_empty()
+        .push(&transaction_id);
+
+    Ok((url, token))
 }
 
 pub struct WebhookNoop {
-    pub url: String,
+    pub url: Secret,
 }
 
 #[async_trait]
 impl Webhook for WebhookNoop {
     async fn push(&self, item: FeedItem) -> Result<()> {
-        println!("{:#?}", item);
+        self.deliver(self.render(&item)?)
+            .await?
+            .error_for_status()?;
 
         Ok(())
     }
 
+    async fn deliver(&self, payload: String) -> Result<Delivery> {
+        println!("{}", payload);
+
+        Ok(Delivery {
+            status: None,
+            body: String::new(),
+        })
+    }
+
     async fn save(&self, db: &Pool<Sqlite>) -> Result<i64> {
+        let url = self.url.expose();
+
         sqlx::query!(
             r#"
             INSERT INTO webhooks (url)
             VALUES (?)
             ON CONFLICT (url) DO NOTHING
             "#,
-            self.url,
+            url,
         )
         .execute(db)
         .await?;
@@ -181,7 +421,7 @@ impl Webhook for WebhookNoop {
             r#"
             SELECT id FROM webhooks WHERE url = ?
             "#,
-            self.url,
+            url,
         )
         .fetch_one(db)
         .await?;
@@ -189,15 +429,111 @@ impl Webhook for WebhookNoop {
         Ok(result.id)
     }
 
-    fn url(&self) -> String {
+    fn render(&self, item: &FeedItem) -> Result<String> {
+        Ok(format!("{:#?}", item))
+    }
+
+    fn render_digest(&self, items: &[FeedItem]) -> Result<Vec<DigestMessage>> {
+        Ok(vec![DigestMessage {
+            payload: format!("{:#?}", items),
+            items: items.len(),
+        }])
+    }
+
+    fn url(&self) -> Secret {
         self.url.clone()
     }
+
+    fn kind(&self) -> &'static str {
+        "stdout"
+    }
+}
+
+async fn post_json(client: &HttpClient, url: &Secret, body: String) -> Result<Delivery> {
+    send_json(client.post(url.expose())?, body).await
+}
+
+async fn send_json(request: reqwest::RequestBuilder, body: String) -> Result<Delivery> {
+    // Errors of reqwest contain the url, which is the secret
+    let response = request
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+
+    let status = response.status().as_u16();
+    let body = match read_body(response, MAX_RESPONSE_SIZE).await {
+        Ok(body) => String::from_utf8_lossy(&body).to_string(),
+        Err(e) => match e.downcast_ref::<LimitExceeded>() {
+            Some(limit) => format!("<{}>", limit),
+            None => "<failed to read response>".to_string(),
+        },
+    };
+
+    Ok(Delivery {
+        status: Some(status),
+        body,
+    })
+}
+
+/// Returns the text of a notification about `item`: its title and its
+/// summary, escaped for the target with `escape`, and its link
+fn message_text(item: &FeedItem, escape: fn(&str) -> String) -> String {
+    let mut parts = vec![];
+    if let Some(title) = item.notification_title() {
+        parts.push(escape(&title));
+    }
+    if let Some(summary) = item.notification_summary() {
+        parts.push(escape(summary));
+    }
+    parts.push(item.link.clone());
+
+    parts.join("\n\n")
+}
+
+/// Renders `items` as a list, split into messages of at most `max_len`
+/// characters, with the number of items of every message; items that
+/// don't fit into a message on their own are truncated. Titles are
+/// escaped for the target with `escape`.
+fn split_digest(
+    items: &[FeedItem],
+    max_len: usize,
+    escape: fn(&str) -> String,
+) -> Vec<(String, usize)> {
+    let mut messages = vec![];
+    let mut message = String::new();
+    let mut count = 0;
+
+    for item in items {
+        let entry = match item.notification_title() {
+            Some(t) => format!("{}\n{}", escape(&t), item.link),
+            None => item.link.clone(),
+        };
+        let entry: String = entry.chars().take(max_len).collect();
+
+        let len = message.chars().count();
+        if len > 0 && len + 2 + entry.chars().count() > max_len {
+            messages.push((std::mem::take(&mut message), count));
+            count = 0;
+        }
+        if !message.is_empty() {
+            message.push_str("\n\n");
+        }
+        message.push_str(&entry);
+        count += 1;
+    }
+    if !message.is_empty() {
+        messages.push((message, count));
+    }
+
+    messages
 }
 
 pub struct Webhooks {}
 
 impl Webhooks {
-    pub async fn get(db: &Pool<Sqlite>, id: i64) -> Result<Box<dyn Webhook>> {
+    pub async fn get(db: &Pool<Sqlite>, id: i64, client: &HttpClient) -> Result<Box<dyn Webhook>> {
         let result = sqlx::query!(
             r#"
             SELECT url
@@ -209,19 +545,56 @@ impl Webhooks {
         .fetch_one(db)
         .await?;
 
-        webhook_from_url(result.url)
+        webhook_from_url(Secret::new(result.url), client)
     }
 }
 
-pub fn webhook_from_url(url: String) -> Result<Box<dyn Webhook>> {
-    if url == "-" {
+pub fn webhook_from_url(url: Secret, client: &HttpClient) -> Result<Box<dyn Webhook>> {
+    let client = client.clone();
+    let u = url.expose();
+    if u == "-" {
         return Ok(Box::new(WebhookNoop { url }));
     }
-    if url.contains("https://discordapp.com/api") || url.contains("https://discord.com/api") {
-        return Ok(Box::new(WebhookDiscord { url }));
+    if u.contains("https://discordapp.com/api") || u.contains("https://discord.com/api") {
+        return Ok(Box::new(WebhookDiscord { url, client }));
+    }
+    if u.contains("https://hooks.slack.com") {
+        return Ok(Box::new(WebhookSlack { url, client }));
     }
-    if url.contains("https://hooks.slack.com") {
-        return Ok(Box::new(WebhookSlack { url }));
+    if u.contains("/_matrix/client/") && u.contains("/send/m.room.message") {
+        return Ok(Box::new(WebhookMatrix { url, client }));
     }
     Err(anyhow!("unknown webhook target: '{}'", url))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_token_is_sent_as_header() {
+        let webhook_url = "https://matrix.example.org/_matrix/client/v3/rooms/!abc:example.org/send/m.room.message?access_token=secret&v=1";
+        let (url, token) = matrix_send_url(webhook_url).unwrap();
+
+        assert_eq!(token.as_deref(), Some("secret"));
+        assert_eq!(url.query(), Some("v=1"));
+        assert!(url.path().starts_with(
+            "/_matrix/client/v3/rooms/!abc:example.org/send/m.room.message/feedcruncher."
+        ));
+
+        // Every message gets a new transaction id
+        let (other, _) = matrix_send_url(webhook_url).unwrap();
+        assert_ne!(other.path(), url.path());
+    }
+
+    #[test]
+    fn matrix_message_has_html_body() {
+        let message =
+            MatrixMessage::new("Tom & <Jerry>\n\nhttps://example.com/?a=1&copy=2".to_string());
+
+        assert_eq!(
+            message.formatted_body,
+            "Tom &amp; &lt;Jerry&gt;<br><br>https://example.com/?a=1&amp;copy=2"
+        );
+    }
+}